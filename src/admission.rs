@@ -0,0 +1,338 @@
+use std::net::Shutdown;
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Sender, TrySendError};
+
+use crate::{Request, Cache};
+use crate::headers::Headers;
+use crate::config::ServerConfig;
+use crate::errors::Errors;
+use crate::io;
+
+/// Chunks read off of clients that the assembler
+/// hasn't written to their spool yet.
+#[derive(Debug, Default)]
+pub struct Load {
+  queued_bytes: AtomicU64,
+  queued_chunks: AtomicU32,
+  /// chunk bytes being read or queued, held from
+  /// before the body is read until it's written
+  in_flight: Mutex<u64>,
+  freed: Condvar
+}
+
+impl Load {
+  pub fn queued_bytes(&self) -> u64 {
+    return self.queued_bytes.load(Ordering::SeqCst);
+  }
+
+  pub fn queued_chunks(&self) -> u32 {
+    return self.queued_chunks.load(Ordering::SeqCst);
+  }
+
+  /// Counts a chunk handed to the assembler.
+  pub fn reserve(&self, chunk_length: usize) {
+    self.queued_bytes.fetch_add(chunk_length as u64, Ordering::SeqCst);
+    self.queued_chunks.fetch_add(1, Ordering::SeqCst);
+  }
+
+  /// Uncounts a chunk once the assembler is done with
+  /// it, and frees its bytes from the in flight budget.
+  pub fn release(&self, chunk_length: usize) {
+    self.queued_bytes.fetch_sub(chunk_length as u64, Ordering::SeqCst);
+    self.queued_chunks.fetch_sub(1, Ordering::SeqCst);
+    self.free(chunk_length as u64);
+  }
+
+  /// Waits for room in the budget to read a chunk body of the
+  /// length. A chunk bigger than the whole budget only has to
+  /// wait for everything else to clear. False if there still
+  /// isn't room after the timeout. A budget of 0 never waits.
+  pub fn acquire(&self, chunk_length: u64, budget: u64, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut in_flight = self.in_flight.lock().expect("Unhandled lock on in flight bytes");
+
+    while budget > 0 && *in_flight > 0 && *in_flight + chunk_length > budget {
+      let now = Instant::now();
+      if now >= deadline {
+        return false;
+      }
+
+      in_flight = self.freed.wait_timeout(in_flight, deadline - now)
+        .expect("Unhandled lock on in flight bytes").0;
+    }
+
+    *in_flight += chunk_length;
+    return true;
+  }
+
+  /// Gives back bytes taken with `acquire` for a
+  /// chunk that never made it to the assembler.
+  pub fn free(&self, chunk_length: u64) {
+    let mut in_flight = self.in_flight.lock().expect("Unhandled lock on in flight bytes");
+    *in_flight = in_flight.saturating_sub(chunk_length);
+    self.freed.notify_all();
+  }
+
+  pub fn in_flight_bytes(&self) -> u64 {
+    return *self.in_flight.lock().expect("Unhandled lock on in flight bytes");
+  }
+}
+
+/// The config's limits on load, kept where they can be
+/// changed while the server runs. See ServerHandle::reload.
+#[derive(Debug, Default)]
+pub struct Limits {
+  max_leases: AtomicUsize,
+  max_queued_bytes: AtomicU64,
+  max_in_flight_bytes: AtomicU64,
+  retry_after_ms: AtomicU64,
+  max_lease_writers: AtomicU32
+}
+
+impl Limits {
+  pub fn new(config: &ServerConfig) -> Limits {
+    let limits = Limits::default();
+    limits.set(config);
+    return limits;
+  }
+
+  /// Takes on the limits of the config. Requests already
+  /// admitted carry on under the old ones.
+  pub fn set(&self, config: &ServerConfig) {
+    self.max_leases.store(config.max_leases, Ordering::SeqCst);
+    self.max_queued_bytes.store(config.max_queued_bytes, Ordering::SeqCst);
+    self.max_in_flight_bytes.store(config.max_in_flight_bytes, Ordering::SeqCst);
+    self.retry_after_ms.store(config.retry_after.as_millis() as u64, Ordering::SeqCst);
+    self.max_lease_writers.store(config.max_lease_writers, Ordering::SeqCst);
+  }
+
+  pub fn max_leases(&self) -> usize {
+    return self.max_leases.load(Ordering::SeqCst);
+  }
+
+  pub fn max_queued_bytes(&self) -> u64 {
+    return self.max_queued_bytes.load(Ordering::SeqCst);
+  }
+
+  pub fn max_in_flight_bytes(&self) -> u64 {
+    return self.max_in_flight_bytes.load(Ordering::SeqCst);
+  }
+
+  pub fn retry_after(&self) -> Duration {
+    return Duration::from_millis(self.retry_after_ms.load(Ordering::SeqCst));
+  }
+
+  pub fn max_lease_writers(&self) -> u32 {
+    return self.max_lease_writers.load(Ordering::SeqCst);
+  }
+}
+
+pub const RETRY_HINT_BYTES: usize = 12;
+
+/// Sent after RETRY so the client knows when to come back,
+/// and how busy the server was when it turned them away.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetryHint {
+  pub retry_after_ms: u32,
+  pub queued_chunks: u32,
+  pub leases: u32
+}
+
+impl RetryHint {
+  pub fn new(cache: &Cache) -> RetryHint {
+    let leases = match cache.leases.lock() {
+      Ok(l) => l.len() as u32,
+      Err(_) => 0
+    };
+
+    return RetryHint {
+      retry_after_ms: cache.limits.retry_after().as_millis() as u32,
+      queued_chunks: cache.load.queued_chunks(),
+      leases
+    };
+  }
+
+  pub fn from_bytes(data: &[u8]) -> Result<RetryHint, Errors> {
+    if data.len() != RETRY_HINT_BYTES {
+      return Err(Errors::ParseError("Retry hint is the wrong length".to_string()));
+    }
+
+    return Ok(RetryHint {
+      retry_after_ms: io::util::read_u32(&data[0..4].to_vec())?,
+      queued_chunks: io::util::read_u32(&data[4..8].to_vec())?,
+      leases: io::util::read_u32(&data[8..12].to_vec())?
+    });
+  }
+
+  /// retry_after_ms, queued_chunks, then leases.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::with_capacity(RETRY_HINT_BYTES);
+    data.extend_from_slice(&self.retry_after_ms.to_le_bytes());
+    data.extend_from_slice(&self.queued_chunks.to_le_bytes());
+    data.extend_from_slice(&self.leases.to_le_bytes());
+    return data;
+  }
+}
+
+/// Checks the server has room for a chunk of the length, and
+/// for another lease if the chunk starts one. Checked before the
+/// body is read, so a turned away client hasn't sent it for nothing.
+pub fn admit(cache: &Cache, chunk_length: u32, new_lease: bool) -> Result<(), Errors> {
+  let max_leases = cache.limits.max_leases();
+  if new_lease && max_leases > 0 {
+    let leases = match cache.leases.lock() {
+      Ok(l) => l.len(),
+      Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()))
+    };
+
+    if leases >= max_leases {
+      return Err(Errors::BusyError(RetryHint::new(cache)));
+    }
+  }
+
+  let max_queued_bytes = cache.limits.max_queued_bytes();
+  if max_queued_bytes > 0 && cache.load.queued_bytes() + chunk_length as u64 > max_queued_bytes {
+    return Err(Errors::BusyError(RetryHint::new(cache)));
+  }
+
+  return Ok(());
+}
+
+/// Admits a request as soon as its headers are read, for
+/// backends that buffer the chunk body before handing the request
+/// on. The chunk bytes are taken from the in flight budget without
+/// waiting, so the event loop never blocks and a client turned
+/// away isn't read for nothing. Returns the bytes taken.
+pub fn admit_headers(cache: &Cache, headers: &Headers) -> Result<u64, Errors> {
+  let chunk_length = match headers.chunk_length {
+    Some(l) if headers.is_lease_type() || headers.is_chunk_type() => l,
+    _ => return Ok(0)
+  };
+
+  admit(cache, chunk_length, headers.is_lease_type())?;
+  if !cache.load.acquire(chunk_length as u64, cache.limits.max_in_flight_bytes(), Duration::ZERO) {
+    return Err(Errors::BusyError(RetryHint::new(cache)));
+  }
+
+  return Ok(chunk_length as u64);
+}
+
+/// Hands the request to the process stage. If its queue is
+/// full the client is sent RETRY instead of waiting in line.
+/// Once the stage has stopped, as it does on shutdown, the
+/// client is sent RETRY too and the server should stop.
+pub fn forward(request: Request, process_s: &Sender<Request>, cache: &Cache) -> Result<(), Errors> {
+  let (mut request, result) = match process_s.try_send(request) {
+    Ok(()) => return Ok(()),
+    Err(TrySendError::Full(request)) => (request, Ok(())),
+    Err(TrySendError::Disconnected(request)) => {
+      (request, Err(Errors::UnexpectedError("Process stage has stopped".to_string())))
+    }
+  };
+
+  if request.admitted {
+    let chunk_length = request.headers.as_ref().and_then(|h| h.chunk_length).unwrap_or(0);
+    cache.load.free(chunk_length as u64);
+  }
+  io::write::write_retry(&mut request.client, &RetryHint::new(cache)).ok();
+  request.client.shutdown(Shutdown::Both).ok();
+
+  return result;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Read;
+  use std::collections::{HashMap, HashSet};
+  use crate::headers::HeaderType;
+  use crate::process::Lease;
+  use crate::store::MemoryStore;
+
+  #[test]
+  fn sheds_past_the_limits() {
+    let config = ServerConfig {
+      max_leases: 1,
+      max_queued_bytes: 3000,
+      ..ServerConfig::default()
+    };
+    let cache = Cache::new(Box::new(MemoryStore), &config);
+
+    assert!(admit(&cache, 2000, true).is_ok());
+    cache.load.reserve(2000);
+    assert!(admit(&cache, 1000, false).is_ok());
+    match admit(&cache, 1001, false) {
+      Err(Errors::BusyError(hint)) => assert_eq!(hint.queued_chunks, 1),
+      other => panic!("expected to be shed, got {:?}", other)
+    };
+    cache.load.release(2000);
+
+    cache.leases.lock().unwrap().insert("lease".to_string(), Lease {
+      id: "lease".to_string(),
+      file_name: "admission_test".to_string(),
+      hash: String::new(),
+      file_length: 0,
+      bytes_left: 0,
+      chunks_sent: 0,
+      chunk_nums: HashSet::new(),
+      ns_last_sent: 0,
+      writers: HashMap::new(),
+      finalizing: false,
+      last_chunk_num: None
+    });
+    assert!(admit(&cache, 2000, false).is_ok());
+    match admit(&cache, 2000, true) {
+      Err(Errors::BusyError(hint)) => assert_eq!(hint.leases, 1),
+      other => panic!("expected to be shed, got {:?}", other)
+    };
+  }
+
+  #[test]
+  fn turns_clients_away_once_the_process_stage_stops() {
+    let config = ServerConfig::default();
+    let cache = Cache::new(Box::new(MemoryStore), &config);
+    let (process_s, process_r) = crossbeam_channel::bounded(1);
+    drop(process_r);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let headers = Headers {
+      header_type: HeaderType::CHUNK,
+      lease_id: Some("lease".to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: Some(2000),
+      chunk_num: Some(0),
+      chunk_checksum: None,
+      cancel: None
+    };
+    assert!(cache.load.acquire(2000, 0, Duration::ZERO));
+    let request = Request { client: server, lease: None, headers: Some(headers), chunk: None, response: None, admitted: true };
+
+    assert!(forward(request, &process_s, &cache).is_err());
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(response[0], io::write::RETRY_MESSAGE[0]);
+    assert_eq!(cache.load.in_flight_bytes(), 0);
+  }
+
+  #[test]
+  fn waits_for_room_in_the_budget() {
+    let load = Load::default();
+    let timeout = Duration::from_millis(10);
+
+    assert!(load.acquire(3000, 5000, timeout));
+    assert!(!load.acquire(3000, 5000, timeout));
+    load.free(3000);
+    assert!(load.acquire(3000, 5000, timeout));
+
+    // too big for the budget, but nothing else is in flight
+    load.free(3000);
+    assert!(load.acquire(8000, 5000, timeout));
+    assert_eq!(load.in_flight_bytes(), 8000);
+  }
+}
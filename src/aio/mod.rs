@@ -0,0 +1,235 @@
+use std::future::Future;
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::time::Duration;
+use crossbeam_channel::{bounded, Sender, Receiver};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use log::{warn, error};
+
+use crate::{Request, Cache, start_stages, admission, handle};
+use crate::handle::ServerHandle;
+use crate::config::ServerConfig;
+use crate::errors::Errors;
+use crate::headers::Headers;
+
+/// async equivalents of `headers::read` and `io::read`
+pub mod read;
+/// async equivalents of `io::write`
+pub mod write;
+
+/// Async alternative to `start_server_with_config` for hosts
+/// that already run a tokio runtime. Connections are accepted
+/// and their requests read on the runtime, then handed to the
+/// same process and assembler stages, which keep running on
+/// their own threads. Runs until the future is dropped.
+pub async fn serve(listener: TcpListener, config: ServerConfig) -> Result<(), Errors> {
+  return serve_until(listener, config, std::future::pending(), Duration::ZERO).await;
+}
+
+/// Same as `serve`, until the shutdown future resolves. Then, as
+/// with `ServerHandle::shutdown`, the requests already accepted
+/// finish and the leases are persisted so uploads can resume.
+pub async fn serve_until<F: Future<Output = ()>>(listener: TcpListener, config: ServerConfig, shutdown: F, drain_timeout: Duration) -> Result<(), Errors> {
+  let config = Arc::new(config);
+  let local_addr = match listener.local_addr() {
+    Ok(a) => a,
+    Err(e) => return Err(Errors::UnexpectedError(format!("Failed to get local address: {}", e)))
+  };
+
+  let (done_s, done_r): (Sender<()>, Receiver<()>) = bounded(0);
+  let (shutdown_s, signal) = handle::signal();
+  let (cache, process_s) = start_stages(&config, &done_s, signal)?;
+  drop(done_s);
+  let handle = ServerHandle::new(local_addr, cache.clone(), shutdown_s, done_r);
+
+  let accepting = Accepting(tokio::spawn(accept(listener, config, cache, process_s)));
+  shutdown.await;
+  drop(accepting);
+
+  // waits on the stages' threads off of the runtime
+  return match tokio::task::spawn_blocking(move || handle.shutdown(drain_timeout)).await {
+    Ok(result) => result,
+    Err(_) => Err(Errors::UnexpectedError("Failed to wait for the server to stop".to_string()))
+  };
+}
+
+/// Stops the accept loop, and the process_s it holds, when
+/// dropped. Also when the serve future is, which winds down
+/// the pipeline.
+struct Accepting(JoinHandle<()>);
+
+impl Drop for Accepting {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+async fn accept(listener: TcpListener, config: Arc<ServerConfig>, cache: Arc<Cache>, process_s: Sender<Request>) {
+  loop {
+    match listener.accept().await {
+      Ok((client, _)) => {
+        let cfg = config.clone();
+        let c = cache.clone();
+        let p = process_s.clone();
+        tokio::spawn(async move { receive(client, cfg, c, p).await });
+      },
+      Err(err) => {
+        error!("{}", err);
+      }
+    };
+  }
+}
+
+/// Reads the whole request without blocking the runtime, then
+/// passes it along with a blocking client to the process stage.
+/// Admitted once the headers are in, so a client the server has
+/// no room for is sent RETRY before its chunk body is read.
+async fn receive(mut client: TcpStream, config: Arc<ServerConfig>, cache: Arc<Cache>, process_s: Sender<Request>) {
+  let headers = match read_headers(&mut client, &config).await {
+    Ok(h) => h,
+    Err(Errors::ReadClosedError) => return,
+    Err(e) => {
+      warn!("{:?}", e);
+      write::write_error(&mut client, &e).await.ok();
+      return;
+    }
+  };
+
+  let in_flight = match admission::admit_headers(&cache, &headers) {
+    Ok(b) => b,
+    Err(e) => {
+      write::write_error(&mut client, &e).await.ok();
+      return;
+    }
+  };
+
+  let chunk = match read_chunk(&mut client, &config, &headers).await {
+    Ok(c) => c,
+    Err(e) => {
+      cache.load.free(in_flight);
+      if let Errors::ReadClosedError = e {
+        return;
+      }
+      warn!("{:?}", e);
+      write::write_error(&mut client, &e).await.ok();
+      return;
+    }
+  };
+
+  let client = match client.into_std() {
+    Ok(c) => c,
+    Err(err) => {
+      cache.load.free(in_flight);
+      error!("{}", err);
+      return;
+    }
+  };
+
+  let blocking = client.set_nonblocking(false)
+    .and_then(|_| client.set_read_timeout(Some(config.read_timeout)))
+    .and_then(|_| client.set_write_timeout(Some(config.write_timeout)));
+  if let Err(err) = blocking {
+    cache.load.free(in_flight);
+    error!("{}", err);
+    client.shutdown(Shutdown::Both).ok();
+    return;
+  }
+
+  let request = Request {
+    client,
+    lease: None,
+    headers: Some(headers),
+    chunk,
+    response: None,
+    admitted: true
+  };
+
+  if let Err(e) = admission::forward(request, &process_s, &cache) {
+    error!("{:?}", e);
+  }
+}
+
+async fn read_headers(client: &mut TcpStream, config: &Arc<ServerConfig>) -> Result<Headers, Errors> {
+  return match tokio::time::timeout(config.read_timeout, read::read_headers(client)).await {
+    Ok(result) => result,
+    Err(_) => Err(Errors::TimeoutError("Timed out reading headers".to_string()))
+  };
+}
+
+async fn read_chunk(client: &mut TcpStream, config: &Arc<ServerConfig>, headers: &Headers) -> Result<Option<Vec<u8>>, Errors> {
+  return match headers.chunk_length {
+    Some(chunk_length) => {
+      let deadline = config.chunk_deadline(chunk_length);
+      Ok(Some(read::read_exact_within(client, chunk_length as usize, deadline).await?))
+    },
+    None => Ok(None)
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{Read, Write};
+  use crate::assembler::checksum::{self, Algorithm};
+  use crate::headers::{HeaderType, MIN_CHUNK_BYTES};
+  use crate::headers::write::write_headers;
+  use crate::io::write::OK_MESSAGE;
+  use crate::store::LeaseStore;
+  use crate::store::journal::JournalStore;
+
+  #[test]
+  fn drains_and_keeps_the_leases_on_shutdown() {
+    let storage_dir = std::env::temp_dir().join("rjchunker_aio_test");
+    std::fs::remove_dir_all(&storage_dir).ok();
+    let journal = storage_dir.join("leases.journal");
+    let config = ServerConfig::builder()
+      .storage_dir(&storage_dir)
+      .lease_journal(&journal)
+      .build()
+      .unwrap();
+
+    // the first of two chunks, which leases the file
+    let data = vec![4u8; MIN_CHUNK_BYTES as usize * 2];
+    let chunk = &data[..MIN_CHUNK_BYTES as usize];
+    let headers = Headers {
+      header_type: HeaderType::ERROR,
+      lease_id: None,
+      checksum: Some(format!("sha256:{}", checksum::digest_bytes(&data, Algorithm::SHA256))),
+      file_name: Some("aio_test.bin".to_string()),
+      file_length: Some(data.len() as u32),
+      chunk_length: Some(MIN_CHUNK_BYTES),
+      chunk_num: Some(0),
+      chunk_checksum: Some(crc32c::crc32c(chunk)),
+      cancel: None
+    };
+    let mut frame = Vec::new();
+    write_headers(&headers, &mut frame).unwrap();
+    frame.extend_from_slice(chunk);
+
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let address = listener.local_addr().unwrap();
+
+      let lease = tokio::task::spawn_blocking(move || {
+        let mut server = std::net::TcpStream::connect(address).unwrap();
+        server.write_all(&frame).unwrap();
+        let mut response = Vec::new();
+        server.read_to_end(&mut response).unwrap();
+        return response;
+      });
+      let shutdown = async {
+        assert_eq!(lease.await.unwrap()[0], OK_MESSAGE[0]);
+      };
+
+      serve_until(listener, config, shutdown, Duration::from_secs(5)).await.unwrap();
+    });
+
+    let leases = JournalStore::open(&journal).unwrap().load().unwrap();
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].file_name, "aio_test.bin");
+
+    std::fs::remove_dir_all(&storage_dir).ok();
+  }
+}
@@ -0,0 +1,98 @@
+use std::io::{Cursor, ErrorKind};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::errors::Errors;
+use crate::headers::Headers;
+use crate::headers::read::header_length;
+use crate::headers::read as sync;
+
+/// Async equivalent of `io::read::read_exact`.
+pub async fn read_exact<R: AsyncRead + Unpin>(client: &mut R, byte_amount: usize) -> Result<Vec<u8>, Errors> {
+  let mut data = vec![0u8; byte_amount];
+  let mut read_bytes = 0;
+
+  while read_bytes < byte_amount {
+    match client.read(&mut data[read_bytes..]).await {
+      Ok(0) => {
+        if read_bytes == 0 {
+          return Err(Errors::ReadClosedError);
+        }
+        return Err(Errors::ReadLengthError(format!("Stream ended after {} of {} bytes", read_bytes, byte_amount)));
+      },
+      Ok(length) => {
+        read_bytes += length;
+      },
+      Err(e) => {
+        match e.kind() {
+          ErrorKind::Interrupted => continue,
+          _ => return Err(Errors::ReadError(format!("Failed to read bytes: {}", e)))
+        };
+      }
+    };
+  }
+
+  return Ok(data);
+}
+
+/// Same as `read_exact`, but the bytes also have to arrive before
+/// the deadline.
+pub async fn read_exact_within<R: AsyncRead + Unpin>(client: &mut R, byte_amount: usize, deadline: Option<Instant>) -> Result<Vec<u8>, Errors> {
+  let deadline = match deadline {
+    Some(d) => tokio::time::Instant::from_std(d),
+    None => return read_exact(client, byte_amount).await
+  };
+
+  return match tokio::time::timeout_at(deadline, read_exact(client, byte_amount)).await {
+    Ok(result) => result,
+    Err(_) => Err(Errors::TimeoutError(format!("Too slow reading {} bytes", byte_amount)))
+  };
+}
+
+/// Async equivalent of `headers::read::read_headers`. The headers
+/// are read whole, then parsed by the blocking reader so both
+/// validate them the same way.
+pub async fn read_headers<R: AsyncRead + Unpin>(client: &mut R) -> Result<Headers, Errors> {
+  let mut data = read_exact(client, 1).await?;
+  let rest = read_exact(client, header_length(data[0]) - 1).await?;
+  data.extend(rest);
+
+  return sync::read_headers(&mut Cursor::new(data));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::io::util;
+
+  #[test]
+  fn reads_the_same_headers_as_the_blocking_reader() {
+    let lease_id = "936da01f-9abd-4d9d-80c7-02af85c822a8";
+    let mut frame = vec![0b0011_0001u8];
+    frame.extend(util::uuid_bytes(lease_id).unwrap());
+    frame.extend(&2000u32.to_le_bytes());
+    frame.extend(&3u32.to_le_bytes());
+    frame.extend(&[9u8; 4]);
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut client = &frame[..];
+    let headers = runtime.block_on(read_headers(&mut client)).unwrap();
+    assert!(headers.is_chunk_type());
+    assert_eq!(headers.lease_id.unwrap(), lease_id);
+    assert_eq!(headers.chunk_length, Some(2000));
+    assert_eq!(headers.chunk_num, Some(3));
+
+    // the chunk body is left unread
+    assert_eq!(client, &[9u8; 4]);
+  }
+
+  #[test]
+  fn reports_a_closed_stream() {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut client: &[u8] = &[];
+    match runtime.block_on(read_headers(&mut client)) {
+      Err(Errors::ReadClosedError) => (),
+      other => panic!("expected a closed stream, got {:?}", other)
+    };
+  }
+}
@@ -0,0 +1,110 @@
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::errors::Errors;
+use crate::admission::RetryHint;
+use crate::io::write::{
+  error_bytes, OK_MESSAGE, ERR_MESSAGE, CONTINUE_MESSAGE, RETRY_MESSAGE, NO_LEASE_MESSAGE,
+  LEASE_IN_USE_MESSAGE, CHECKSUM_MISMATCH_MESSAGE, DUPLICATE_CHUNK_MESSAGE, OUT_OF_RANGE_MESSAGE,
+  FILE_EXISTS_MESSAGE
+};
+
+/// Writes the bytes, failing with a WriteError that says what they were.
+async fn write_message<W: AsyncWrite + Unpin>(client: &mut W, data: &[u8], what: &str) -> Result<(), Errors> {
+  return match client.write_all(data).await {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError(format!("Failed to write {}", what)))
+  };
+}
+
+pub async fn write_string<W: AsyncWrite + Unpin>(client: &mut W, message: &str) -> Result<(), Errors> {
+  return write_message(client, message.as_bytes(), "message").await;
+}
+
+pub async fn write_bytes<W: AsyncWrite + Unpin>(client: &mut W, data: &[u8]) -> Result<(), Errors> {
+  return write_message(client, data, "bytes").await;
+}
+
+pub async fn write_ok<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &OK_MESSAGE, "ok").await;
+}
+
+/// OK followed by the response, if there is one.
+pub async fn write_response<W: AsyncWrite + Unpin>(client: &mut W, response: &Option<Vec<u8>>) -> Result<(), Errors> {
+  write_ok(client).await?;
+
+  if let Some(response) = response {
+    write_bytes(client, response).await?;
+  }
+
+  return Ok(());
+}
+
+pub async fn write_err<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &ERR_MESSAGE, "err").await;
+}
+
+pub async fn write_continue<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &CONTINUE_MESSAGE, "continue").await;
+}
+
+/// RETRY followed by the hint of when to retry.
+pub async fn write_retry<W: AsyncWrite + Unpin>(client: &mut W, hint: &RetryHint) -> Result<(), Errors> {
+  let mut message = RETRY_MESSAGE.to_vec();
+  message.extend(hint.to_bytes());
+
+  return write_message(client, &message, "retry").await;
+}
+
+pub async fn write_no_lease<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &NO_LEASE_MESSAGE, "no lease").await;
+}
+
+pub async fn write_lease_in_use<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &LEASE_IN_USE_MESSAGE, "lease in use").await;
+}
+
+pub async fn write_checksum_mismatch<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &CHECKSUM_MISMATCH_MESSAGE, "checksum mismatch").await;
+}
+
+pub async fn write_duplicate_chunk<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &DUPLICATE_CHUNK_MESSAGE, "duplicate chunk").await;
+}
+
+pub async fn write_out_of_range<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &OUT_OF_RANGE_MESSAGE, "out of range").await;
+}
+
+pub async fn write_file_exists<W: AsyncWrite + Unpin>(client: &mut W) -> Result<(), Errors> {
+  return write_message(client, &FILE_EXISTS_MESSAGE, "file exists").await;
+}
+
+/// Responds with the message for the error. Errors without
+/// a message of their own are sent as ERR.
+pub async fn write_error<W: AsyncWrite + Unpin>(client: &mut W, error: &Errors) -> Result<(), Errors> {
+  return write_message(client, &error_bytes(error), "error").await;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn writes_the_same_bytes_as_the_blocking_writer() {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let hint = RetryHint::default();
+
+    let mut client = Vec::new();
+    runtime.block_on(write_response(&mut client, &Some(vec![7, 8]))).unwrap();
+    assert_eq!(client, vec![OK_MESSAGE[0], 7, 8]);
+
+    let mut client = Vec::new();
+    runtime.block_on(write_retry(&mut client, &hint)).unwrap();
+    assert_eq!(client, error_bytes(&Errors::BusyError(hint)));
+
+    let mut client = Vec::new();
+    runtime.block_on(write_no_lease(&mut client)).unwrap();
+    runtime.block_on(write_error(&mut client, &Errors::FileExistsError(String::new()))).unwrap();
+    assert_eq!(client, vec![NO_LEASE_MESSAGE[0], FILE_EXISTS_MESSAGE[0]]);
+  }
+}
@@ -0,0 +1,145 @@
+use std::io::{Read, BufReader};
+use std::fs::File;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::errors::Errors;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+  SHA256,
+  SHA512,
+}
+
+impl Algorithm {
+  /// Works out the algorithm and hex digest from a lease hash. The
+  /// hash is either prefixed with the algorithm (`sha256:<hex>`)
+  /// or a bare hex digest, in which case the length decides.
+  pub fn from_hash(hash: &str) -> Option<(Algorithm, String)> {
+    let hash = hash.trim_end_matches('\0').trim();
+
+    let (algorithm, digest) = match hash.find(':') {
+      Some(i) => {
+        let algorithm = match hash[..i].to_lowercase().as_str() {
+          "sha256" => Algorithm::SHA256,
+          "sha512" => Algorithm::SHA512,
+          _ => return None
+        };
+        (algorithm, &hash[i + 1..])
+      },
+      None => {
+        let algorithm = match hash.len() {
+          64 => Algorithm::SHA256,
+          128 => Algorithm::SHA512,
+          _ => return None
+        };
+        (algorithm, hash)
+      }
+    };
+
+    if digest.len() != algorithm.hex_length() || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+      return None;
+    }
+
+    return Some((algorithm, digest.to_lowercase()));
+  }
+
+  pub fn hex_length(&self) -> usize {
+    return match self {
+      Algorithm::SHA256 => 64,
+      Algorithm::SHA512 => 128,
+    };
+  }
+}
+
+/// Hex digest of the file at the location.
+pub fn digest_file(location: &str, algorithm: Algorithm) -> Result<String, Errors> {
+  return match algorithm {
+    Algorithm::SHA256 => digest_with(location, Sha256::new()),
+    Algorithm::SHA512 => digest_with(location, Sha512::new()),
+  };
+}
+
+/// Hex digest of the bytes.
+pub fn digest_bytes(data: &[u8], algorithm: Algorithm) -> String {
+  return match algorithm {
+    Algorithm::SHA256 => hex(&Sha256::digest(data)),
+    Algorithm::SHA512 => hex(&Sha512::digest(data)),
+  };
+}
+
+fn hex(digest: &[u8]) -> String {
+  return digest.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn digest_with<D: Digest>(location: &str, mut hasher: D) -> Result<String, Errors> {
+  let file = match File::open(location) {
+    Ok(f) => f,
+    Err(_) => return Err(Errors::FileIOError("Failed to open file for checksum".to_string()))
+  };
+
+  let mut reader = BufReader::new(file);
+  let mut buffer = vec![0u8; 64 * 1024];
+
+  loop {
+    let length = match reader.read(&mut buffer) {
+      Ok(l) => l,
+      Err(_) => return Err(Errors::FileIOError("Failed to read file for checksum".to_string()))
+    };
+
+    if length == 0 {
+      break;
+    }
+
+    hasher.update(&buffer[..length]);
+  }
+
+  return Ok(hex(&hasher.finalize()));
+}
+
+/// Compares the digest of the file at the location to the lease hash.
+pub fn verify(location: &str, hash: &str) -> Result<(), Errors> {
+  let (algorithm, expected) = match Algorithm::from_hash(hash) {
+    Some(h) => h,
+    None => return Err(Errors::InvalidRequest("Unsupported checksum".to_string()))
+  };
+
+  let actual = digest_file(location, algorithm)?;
+  if actual != expected {
+    return Err(Errors::ChecksumError(format!("expected {} but assembled {}", expected, actual)));
+  }
+
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+  #[test]
+  fn parses_prefixed_and_bare_hashes() {
+    let prefixed = format!("sha256:{}", ABC_SHA256.to_uppercase());
+    assert_eq!(Algorithm::from_hash(&prefixed), Some((Algorithm::SHA256, ABC_SHA256.to_string())));
+
+    let padded = format!("{}\0\0\0", ABC_SHA256);
+    assert_eq!(Algorithm::from_hash(&padded), Some((Algorithm::SHA256, ABC_SHA256.to_string())));
+
+    assert_eq!(Algorithm::from_hash("md5:900150983cd24fb0d6963f7d28e17f72"), None);
+    assert_eq!(Algorithm::from_hash("abc"), None);
+  }
+
+  #[test]
+  fn verifies_file_digest() {
+    let location = std::env::temp_dir().join("rjchunker_checksum_test").to_string_lossy().to_string();
+    std::fs::write(&location, b"abc").unwrap();
+
+    assert!(verify(&location, ABC_SHA256).is_ok());
+    match verify(&location, &ABC_SHA256.replace("b", "c")) {
+      Err(Errors::ChecksumError(_)) => (),
+      r => panic!("expected checksum error, got {:?}", r)
+    }
+
+    std::fs::remove_file(&location).ok();
+  }
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+use std::net::Shutdown;
+use std::fs;
+use std::path::Path;
+use std::io::ErrorKind;
+use crossbeam_channel::Receiver;
+use log::warn;
+
+use crate::{Cache, Request};
+use crate::process::Lease;
+use crate::assembler::{spool, checksum};
+use crate::errors::Errors;
+use crate::config::ServerConfig;
+use crate::io;
+
+/// Assembles uploads whose final chunk has been written. Runs
+/// on its own thread so rearranging large files doesn't hold
+/// up the assembler workers.
+pub fn finalizer(config: Arc<ServerConfig>, cache: Arc<Cache>, finalizer_r: Receiver<Request>) {
+  while let Ok(mut request) = finalizer_r.recv() {
+    match finalize(&config, &cache, &mut request) {
+      Ok(()) => {
+        io::write::write_response(&mut request.client, &request.response).ok();
+        request.client.shutdown(Shutdown::Both).ok();
+      },
+      Err(e) => {
+        warn!("{:?}", e);
+        io::write::write_error(&mut request.client, &e).ok();
+        request.client.shutdown(Shutdown::Both).ok();
+      }
+    };
+  }
+}
+
+fn finalize(config: &Arc<ServerConfig>, cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
+  return finalize_lease(config, cache, request.lease.as_ref().unwrap());
+}
+
+/// Assembles the lease's file, checks it and publishes it, then
+/// drops the lease. Also run at startup for leases whose spool
+/// held the whole file before the finalizer got to it.
+pub fn finalize_lease(config: &ServerConfig, cache: &Cache, lease: &Lease) -> Result<(), Errors> {
+  let spool_location = spool::location(&config.incoming_path(), &lease.id, &lease.file_name);
+  let part = format!("{}.part", spool_location);
+  let quarantine = quarantine_location(&config.quarantine_path(), &lease.id, &lease.file_name);
+
+  // assembled and checked next to the spool, so nothing
+  // shows up in complete until the file is whole.
+  let result = spool::assemble(&spool_location, &part, lease.file_length)
+    .and_then(|_| verify(&part, &quarantine, &lease.hash))
+    .and_then(|_| {
+      let published = publish(&config.complete_path(), &part, &lease.id, &lease.file_name);
+      // a checked upload that can't be published, say because
+      // complete has a file of the same name, is kept in
+      // quarantine rather than thrown away
+      if published.is_err() && fs::rename(&part, &quarantine).is_err() {
+        warn!("Left {} in place, it couldn't be quarantined", part);
+      }
+      published
+    });
+
+  // the lease can't receive any more chunks at this point,
+  // so it's gone whether or not the file came together.
+  fs::remove_file(&spool_location).ok();
+
+  if let Ok(mut leases) = cache.leases.lock() {
+    leases.remove(&lease.id);
+  } else {
+    return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()));
+  }
+  cache.store.remove(&lease.id)?;
+
+  return result;
+}
+
+/// Where a finished upload ends up.
+pub fn output_location(complete_dir: &Path, file_name: &str) -> String {
+  return complete_dir.join(file_name).to_string_lossy().to_string();
+}
+
+/// Where files that don't match their lease hash are moved to.
+pub fn quarantine_location(quarantine_dir: &Path, lease_id: &str, file_name: &str) -> String {
+  return quarantine_dir.join(format!("{}_{}", lease_id, file_name)).to_string_lossy().to_string();
+}
+
+/// Moves the assembled file into the complete directory, so it
+/// appears there whole or not at all and never replaces a file
+/// of the same name. If the directories are on different file
+/// systems the file is copied next to its destination first,
+/// then moved into place. The part is left where it is when
+/// it can't be published.
+fn publish(complete_dir: &Path, part: &str, lease_id: &str, file_name: &str) -> Result<(), Errors> {
+  let output = output_location(complete_dir, file_name);
+
+  let result = match move_new(Path::new(part), Path::new(&output)) {
+    Err(e) if e.kind() != ErrorKind::AlreadyExists => {
+      let staged = complete_dir.join(format!(".{}_{}", lease_id, file_name));
+      let result = fs::copy(part, &staged)
+        .and_then(|_| fs::File::open(&staged)?.sync_all())
+        .and_then(|_| move_new(&staged, Path::new(&output)));
+      match result {
+        Ok(()) => {
+          fs::remove_file(part).ok();
+        },
+        Err(_) => {
+          fs::remove_file(&staged).ok();
+        }
+      };
+      result
+    },
+    result => result
+  };
+
+  return match result {
+    Ok(()) => Ok(()),
+    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+      Err(Errors::FileExistsError(format!("{} is already in the complete directory", file_name)))
+    },
+    Err(_) => Err(Errors::FileIOError("Failed to move file into the complete directory".to_string()))
+  };
+}
+
+/// Moves the file without replacing one at the destination. Hard
+/// linked then unlinked where the file system has hard links,
+/// else renamed, as on the vfat and exFAT media edge devices
+/// tend to have.
+fn move_new(from: &Path, to: &Path) -> std::io::Result<()> {
+  return match fs::hard_link(from, to) {
+    Ok(()) => {
+      fs::remove_file(from).ok();
+      Ok(())
+    },
+    Err(e) if e.kind() == ErrorKind::Unsupported || e.kind() == ErrorKind::PermissionDenied => rename_new(from, to),
+    Err(e) => Err(e)
+  };
+}
+
+/// A rename that fails with AlreadyExists rather than replace
+/// the destination. Atomic on linux, where the file system
+/// supports it.
+#[cfg(target_os = "linux")]
+fn rename_new(from: &Path, to: &Path) -> std::io::Result<()> {
+  use std::ffi::CString;
+  use std::os::unix::ffi::OsStrExt;
+
+  let from_c = CString::new(from.as_os_str().as_bytes())?;
+  let to_c = CString::new(to.as_os_str().as_bytes())?;
+  // the paths are nul terminated and outlive the call
+  let renamed = unsafe {
+    libc::renameat2(libc::AT_FDCWD, from_c.as_ptr(), libc::AT_FDCWD, to_c.as_ptr(), libc::RENAME_NOREPLACE)
+  };
+  if renamed == 0 {
+    return Ok(());
+  }
+
+  let error = std::io::Error::last_os_error();
+  return match error.raw_os_error() {
+    Some(libc::EINVAL) | Some(libc::ENOSYS) => rename_checked(from, to),
+    _ => Err(error)
+  };
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rename_new(from: &Path, to: &Path) -> std::io::Result<()> {
+  return rename_checked(from, to);
+}
+
+/// Renames unless the destination exists. Only the finalizer
+/// publishes, so nothing else should take the name in between.
+fn rename_checked(from: &Path, to: &Path) -> std::io::Result<()> {
+  if to.symlink_metadata().is_ok() {
+    return Err(std::io::Error::new(ErrorKind::AlreadyExists, "destination exists"));
+  }
+
+  return fs::rename(from, to);
+}
+
+fn verify(part: &str, quarantine: &str, hash: &str) -> Result<(), Errors> {
+  let result = checksum::verify(part, hash);
+
+  match result {
+    Ok(()) => (),
+    Err(Errors::ChecksumError(_)) => {
+      if fs::rename(part, quarantine).is_err() {
+        fs::remove_file(part).ok();
+      }
+    },
+    Err(_) => {
+      fs::remove_file(part).ok();
+    }
+  };
+
+  return result;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn publishes_without_replacing_a_file() {
+    let dir = std::env::temp_dir().join("rjchunker_publish_test");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    let part = dir.join("upload.part").to_string_lossy().to_string();
+    let lease_id = "7e1a3c5b-9d2f-4a6e-8b0c-2d4f6a8c0e13";
+
+    fs::write(&part, b"first").unwrap();
+    publish(&dir, &part, lease_id, "upload.bin").unwrap();
+    assert!(!Path::new(&part).exists());
+
+    fs::write(&part, b"second").unwrap();
+    match publish(&dir, &part, lease_id, "upload.bin") {
+      Err(Errors::FileExistsError(_)) => (),
+      result => panic!("Expected a file exists error, got {:?}", result)
+    };
+    assert_eq!(fs::read(dir.join("upload.bin")).unwrap(), b"first");
+    assert!(Path::new(&part).exists());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn keeps_the_part_when_it_cant_be_published() {
+    let dir = std::env::temp_dir().join("rjchunker_publish_fail_test");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    let part = dir.join("upload.part").to_string_lossy().to_string();
+    fs::write(&part, b"checked").unwrap();
+
+    match publish(&dir.join("missing"), &part, "7e1a3c5b-9d2f-4a6e-8b0c-2d4f6a8c0e13", "upload.bin") {
+      Err(Errors::FileIOError(_)) => (),
+      result => panic!("Expected a file io error, got {:?}", result)
+    };
+    assert_eq!(fs::read(&part).unwrap(), b"checked");
+
+    fs::remove_dir_all(&dir).ok();
+  }
+
+  #[test]
+  fn renames_without_replacing_a_file() {
+    let dir = std::env::temp_dir().join("rjchunker_rename_test");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    let (first, second, output) = (dir.join("first"), dir.join("second"), dir.join("output"));
+    fs::write(&first, b"first").unwrap();
+    fs::write(&second, b"second").unwrap();
+
+    // what publishing falls back to without hard links
+    rename_new(&first, &output).unwrap();
+    assert!(!first.exists());
+    assert_eq!(rename_new(&second, &output).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(rename_checked(&second, &output).unwrap_err().kind(), ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&output).unwrap(), b"first");
+    assert!(second.exists());
+
+    fs::remove_dir_all(&dir).ok();
+  }
+}
@@ -1,51 +1,78 @@
-use std::io::Write;
 use std::sync::{Mutex, Arc};
 use std::net::Shutdown;
-use std::thread;
-use std::fs::OpenOptions;
-use num_cpus;
-use crossbeam_channel::{unbounded, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crossbeam_channel::{bounded, Sender, Receiver};
+use log::{warn, error};
 
 use crate::{Cache, Request};
 use crate::headers::HeaderType;
+use crate::process::Lease;
 use crate::errors::Errors;
+use crate::config::ServerConfig;
 use crate::io;
 
-pub fn start(cache: Arc<Cache>, assembler_r: Receiver<Request>) {
-  let worker_s = start_workers(cache);
+pub mod spool;
+pub mod checksum;
+pub mod finalize;
 
-  loop {
-    if let Ok(request) = assembler_r.recv() {
-      worker_s.send(request).expect("Unhandled sent to worker thread");
-    }
+pub fn start(config: Arc<ServerConfig>, cache: Arc<Cache>, assembler_r: Receiver<Request>) {
+  let (worker_s, workers) = start_workers(config, cache);
+
+  while let Ok(request) = assembler_r.recv() {
+    worker_s.send(request).expect("Unhandled sent to worker thread");
+  }
+
+  // the workers finish writing what's queued, then
+  // the finalizer stops once they've all returned
+  drop(worker_s);
+  for worker in workers {
+    worker.join().ok();
   }
 }
 
-fn start_workers(cache: Arc<Cache>) -> Sender<Request> {
-  let (worker_s, worker_r): (Sender<Request>, Receiver<Request>) = unbounded();
+/// Queues are bounded, so a full one holds up the stage
+/// feeding it rather than piling up chunks in memory.
+fn start_workers(config: Arc<ServerConfig>, cache: Arc<Cache>) -> (Sender<Request>, Vec<JoinHandle<()>>) {
+  let (worker_s, worker_r): (Sender<Request>, Receiver<Request>) = bounded(config.queue_depth);
+  let (finalizer_s, finalizer_r): (Sender<Request>, Receiver<Request>) = bounded(config.queue_depth);
   let worker_r = Arc::new(Mutex::new(worker_r));
-  let cores = num_cpus::get();
+  let mut workers = Vec::with_capacity(config.assembler_workers + 1);
 
-  for _ in 0..cores {
+  for _ in 0..config.assembler_workers {
+    let cfg = config.clone();
     let c = cache.clone();
     let r = worker_r.clone();
-    thread::spawn(move || assembler(c, r));
+    let f = finalizer_s.clone();
+    workers.push(thread::spawn(move || assembler(cfg, c, r, f)));
   }
 
-  return worker_s;
+  workers.push(thread::spawn(move || finalize::finalizer(config, cache, finalizer_r)));
+
+  return (worker_s, workers);
 }
 
-fn assembler(cache: Arc<Cache>, worker_r: Arc<Mutex<Receiver<Request>>>) {
+fn assembler(config: Arc<ServerConfig>, cache: Arc<Cache>, worker_r: Arc<Mutex<Receiver<Request>>>, finalizer_s: Sender<Request>) {
   loop {
     let receiver = worker_r.lock().expect("Unhandled lock on worker receiver");
-    let mut request = receiver.recv().expect("Unhandled worker receiver error");
+    let mut request = match receiver.recv() {
+      Ok(r) => r,
+      Err(_) => return
+    };
     drop(receiver);
 
     let result = match request.headers.as_ref().unwrap().header_type {
-      HeaderType::LEASE => handle_lease_request(&cache, &mut request),
-      HeaderType::CHUNK => handle_chunk_request(&cache, &mut request),
-      HeaderType::CANCEL => handle_cancel_request(&cache, &mut request),
-      HeaderType::FINAL => handle_final_request(&cache, &mut request),
+      HeaderType::LEASE => handle_lease_request(&config, &cache, &mut request),
+      HeaderType::CHUNK => handle_chunk_request(&config, &cache, &mut request),
+      HeaderType::CANCEL => handle_cancel_request(&config, &cache, &mut request),
+      HeaderType::FINAL => {
+        // not possible... the assembler marks requests final
+        Err(Errors::UnexpectedError("Final request sent to assembler".to_string()))
+      },
+      HeaderType::STATUS => {
+        // answered by the process stage
+        Err(Errors::UnexpectedError("Status requests are not assembled".to_string()))
+      },
       HeaderType::ERROR => {
         // dunno what you are...
         // shouldn't happen. But kill that client!
@@ -53,97 +80,211 @@ fn assembler(cache: Arc<Cache>, worker_r: Arc<Mutex<Receiver<Request>>>) {
       },
     };
 
+    // written to the spool, or never will be
+    if let Some(chunk) = request.chunk.take() {
+      cache.load.release(chunk.len());
+    }
+
     match result {
       Ok(_) => {
-        io::write::write_ok(&mut request.client).ok();
+        if let HeaderType::FINAL = request.headers.as_ref().unwrap().header_type {
+          // finalizer responds once the file is assembled
+          finalizer_s.send(request).expect("Unhandled send to finalizer thread");
+          continue;
+        }
+
+        io::write::write_response(&mut request.client, &request.response).ok();
         request.client.shutdown(Shutdown::Both).ok();
       },
       Err(e) => {
-        println!("{:?}", e);
-        io::write::write_err(&mut request.client).ok();
+        warn!("{:?}", e);
+        release_lease(&cache, &request);
+        io::write::write_error(&mut request.client, &e).ok();
         request.client.shutdown(Shutdown::Both).ok();
       }
     };
   }
 }
 
-fn handle_lease_request(cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
-  // TODO
-  // create a new file. Check if exists? Shouldnt exist because it's a new uuid
-  // write chunk to file.
-  // edit and save the lease in cache
-  // respond with lease id to client
+/// Frees up the chunk after a failed write so the
+/// client can resend, or the reaper can expire the lease.
+fn release_lease(cache: &Arc<Cache>, request: &Request) {
+  let chunk_num = request.headers.as_ref().and_then(|h| h.chunk_num);
+  if let (Some(lease), Some(chunk_num)) = (request.lease.as_ref(), chunk_num) {
+    if let Ok(mut leases) = cache.leases.lock() {
+      if let Some(cached) = leases.get_mut(&lease.id) {
+        cached.writers.remove(&chunk_num);
+      }
+    }
+  }
+}
+
+fn handle_lease_request(config: &Arc<ServerConfig>, cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
+  write_chunk(config, cache, request)?;
 
-  let lease = request.lease.as_mut().unwrap();
+  let lease = request.lease.as_ref().unwrap();
+  request.response = Some(io::util::uuid_bytes(&lease.id)?);
+
+  return Ok(());
+}
+
+fn handle_chunk_request(config: &Arc<ServerConfig>, cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
+  return write_chunk(config, cache, request);
+}
+
+/// Appends the chunk to the spool and accounts for it on the
+/// cached lease. The request that completes the file is marked
+/// FINAL so it's handed to the finalizer.
+fn write_chunk(config: &Arc<ServerConfig>, cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
+  let lease = request.lease.as_ref().unwrap();
   let headers = request.headers.as_ref().unwrap();
-  let file_name = headers.file_name.as_ref().unwrap();
-  let lease_id = headers.lease_id.as_ref().unwrap();
   let chunk_length = headers.chunk_length.as_ref().unwrap();
   let chunk_num = headers.chunk_num.as_ref().unwrap();
   let chunk = request.chunk.as_ref().unwrap();
 
-  let file_location = format!("{}_{}", lease_id, file_name);
-
-  let chunk_num_bytes: [u8; 4] = chunk_num.to_le_bytes();
-  let chunk_length_bytes: [u8; 4] = chunk_length.to_le_bytes();
-  let mut package: Vec<u8> = Vec::with_capacity(chunk.len() + 4 + 4);
-  package.extend_from_slice(&chunk_num_bytes);
-  package.extend_from_slice(&chunk_length_bytes);
-  package.extend_from_slice(&chunk);
-
-  let result = OpenOptions::new()
-    .append(true)
-    .create(true)
-    .open(file_location)
-    .and_then(|mut file| {
-      file.write_all(&package)?;
-      file.flush()?;
-      return Ok(());
-    });
-  
-  if result.is_err() {
-    return Err(Errors::FileIOError("Failed to write chunk to file".to_string()));
+  let location = spool::location(&config.incoming_path(), &lease.id, &lease.file_name);
+  {
+    // other workers may be writing chunks of the same lease
+    let _spool = cache.spools.lock(&location);
+    spool::append(&location, chunk_num, chunk_length, chunk)?;
   }
 
-  lease.bytes_left = lease.file_length - chunk_length;
-  lease.chunks_sent += 1;
-  lease.in_use = false;
+  let lease = record_chunk(cache, &lease.id, chunk_num, chunk_length, config.min_chunk_bytes)?;
+  let saved = cache.store.save(&lease);
 
-  if let Ok(mut leases) = cache.leases.lock() {
-    leases.insert(lease_id.to_string(), lease.clone());
-  } else {
-    return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()));
+  let complete = lease.bytes_left == 0;
+  request.lease = Some(lease);
+  if complete {
+    // finalized even if the save failed, since a finalizing
+    // lease takes no more chunks and the reaper skips it
+    if let Err(e) = saved {
+      error!("{:?}", e);
+    }
+    request.set_header_type(HeaderType::FINAL);
+    return Ok(());
   }
 
-  io::write::write_string(&mut request.client, lease_id)?;
-
-  return Ok(());
+  return saved;
 }
 
-fn handle_chunk_request(cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
-  // TODO
-  // write chunk to file
-  // edit and save the lease in cache
-  // respond success to client
-  // close connection
-  return Ok(());
+/// Updates the cached lease with the written chunk. The cache
+/// is the one place bytes_left is kept, so completion doesn't
+/// depend on the order chunks arrive in.
+fn record_chunk(cache: &Arc<Cache>, lease_id: &str, chunk_num: &u32, chunk_length: &u32, min_chunk_bytes: u32) -> Result<Lease, Errors> {
+  let mut leases = match cache.leases.lock() {
+    Ok(l) => l,
+    Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()))
+  };
+
+  let lease = match leases.get_mut(lease_id) {
+    Some(l) => l,
+    None => return Err(Errors::UnexpectedError("Lease is missing from the cache".to_string()))
+  };
+
+  if !lease.chunk_nums.contains(chunk_num) {
+    lease.bytes_left = match lease.bytes_left.checked_sub(*chunk_length) {
+      Some(b) => b,
+      None => return Err(Errors::OutOfRangeError("Chunk length exceeds bytes left in the file".to_string()))
+    };
+    lease.chunk_nums.insert(*chunk_num);
+
+    if *chunk_length < min_chunk_bytes {
+      lease.last_chunk_num = Some(*chunk_num);
+    }
+  }
+
+  lease.writers.remove(chunk_num);
+  lease.chunks_sent += 1;
+  lease.ns_last_sent = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards. lol")
+    .as_nanos();
+
+  // a complete lease stays in use while the
+  // finalizer rearranges the chunks into the file.
+  lease.finalizing = lease.bytes_left == 0;
+
+  return Ok(lease.clone());
 }
 
-fn handle_cancel_request(cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
-  // TODO
-  // remove file from database
-  // delete lease in cache
-  // respond success to client
-  // close connection
+/// The lease was already taken out of the cache by the
+/// process stage. Responds with the amount of bytes discarded.
+fn handle_cancel_request(config: &Arc<ServerConfig>, cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
+  let lease = request.lease.as_ref().unwrap();
+
+  cache.store.remove(&lease.id)?;
+  let discarded = spool::discard(&spool::location(&config.incoming_path(), &lease.id, &lease.file_name))?;
+  request.response = Some(discarded.to_le_bytes().to_vec());
+
   return Ok(());
 }
 
-fn handle_final_request(cache: &Arc<Cache>, request: &mut Request) -> Result<(), Errors> {
-  // TODO
-  // write chunk to file
-  // rearrange file chunks. Maybe a background process? Might take some time
-  // delete lease in cache
-  // respond success to client
-  // close connection
-  return Ok(());
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::{HashMap, HashSet};
+  use std::net::{TcpListener, TcpStream};
+  use crate::headers::Headers;
+  use crate::store::LeaseStore;
+  use crate::store::journal::JournalStore;
+
+  #[test]
+  fn cancel_discards_the_spool_and_the_stored_lease() {
+    let storage_dir = std::env::temp_dir().join("rjchunker_cancel_test");
+    let config = Arc::new(ServerConfig { storage_dir: storage_dir.clone(), ..ServerConfig::default() });
+    config.create_dirs().unwrap();
+    let journal = storage_dir.join("leases.journal");
+    std::fs::remove_file(&journal).ok();
+    let cache = Cache::new(Box::new(JournalStore::open(&journal).unwrap()), &config);
+
+    let lease = Lease {
+      id: "cancel".to_string(),
+      file_name: "cancel_test".to_string(),
+      hash: String::new(),
+      file_length: 3000,
+      bytes_left: 1000,
+      chunks_sent: 2,
+      chunk_nums: [0, 1].iter().copied().collect(),
+      ns_last_sent: 0,
+      writers: HashMap::new(),
+      finalizing: false,
+      last_chunk_num: None
+    };
+    cache.store.save(&lease).unwrap();
+    let location = spool::location(&config.incoming_path(), &lease.id, &lease.file_name);
+    std::fs::remove_file(&location).ok();
+    spool::append(&location, &0, &1000, &[1u8; 1000]).unwrap();
+    spool::append(&location, &1, &1000, &[2u8; 1000]).unwrap();
+
+    // the process stage has already taken the lease out of the cache
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let headers = Headers {
+      header_type: HeaderType::CANCEL,
+      lease_id: Some(lease.id.to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: None,
+      chunk_num: None,
+      chunk_checksum: None,
+      cancel: Some(true)
+    };
+    let mut request = Request {
+      client: listener.accept().unwrap().0,
+      lease: Some(lease),
+      headers: Some(headers),
+      chunk: None,
+      response: None,
+      admitted: false
+    };
+    handle_cancel_request(&config, &Arc::new(cache), &mut request).unwrap();
+
+    assert_eq!(request.response, Some(2000u64.to_le_bytes().to_vec()));
+    assert!(!std::path::Path::new(&location).exists());
+    let stored: HashSet<String> = JournalStore::open(&journal).unwrap().load().unwrap().into_iter().map(|l| l.id).collect();
+    assert!(!stored.contains("cancel"));
+
+    std::fs::remove_file(&journal).ok();
+  }
+}
@@ -0,0 +1,259 @@
+use std::io::{Read, Write, Seek, SeekFrom, BufReader, BufWriter, ErrorKind};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+
+use uuid::Uuid;
+
+use crate::errors::Errors;
+use crate::io::util;
+
+pub const RECORD_HEADER_BYTES: u64 = 8;
+pub const LOCK_STRIPES: usize = 64;
+
+/// Location of a single chunk record inside of a spool file.
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+  pub chunk_num: u32,
+  pub length: u32,
+  pub offset: u64
+}
+
+/// Keeps appends to a spool file one at a time. Spools share
+/// a fixed set of locks rather than each getting their own.
+pub struct Locks {
+  stripes: Vec<Mutex<()>>
+}
+
+impl Default for Locks {
+  fn default() -> Locks {
+    return Locks {
+      stripes: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect()
+    };
+  }
+}
+
+impl Locks {
+  pub fn lock(&self, location: &str) -> MutexGuard<'_, ()> {
+    let mut hasher = DefaultHasher::new();
+    location.hash(&mut hasher);
+    let stripe = &self.stripes[hasher.finish() as usize % self.stripes.len()];
+
+    // the guarded data is (), so a poisoned lock is still fine
+    return match stripe.lock() {
+      Ok(g) => g,
+      Err(poisoned) => poisoned.into_inner()
+    };
+  }
+}
+
+/// The spool file chunks for a lease are appended to.
+pub fn location(storage_dir: &Path, lease_id: &str, file_name: &str) -> String {
+  return storage_dir.join(format!("{}_{}", lease_id, file_name)).to_string_lossy().to_string();
+}
+
+/// Appends the chunk to the spool file as a
+/// [chunk_num][chunk_length][bytes] record. Chunks are
+/// reordered by chunk_num when the file is assembled.
+pub fn append(location: &str, chunk_num: &u32, chunk_length: &u32, chunk: &[u8]) -> Result<(), Errors> {
+  let chunk_num_bytes: [u8; 4] = chunk_num.to_le_bytes();
+  let chunk_length_bytes: [u8; 4] = chunk_length.to_le_bytes();
+  let mut package: Vec<u8> = Vec::with_capacity(chunk.len() + 4 + 4);
+  package.extend_from_slice(&chunk_num_bytes);
+  package.extend_from_slice(&chunk_length_bytes);
+  package.extend_from_slice(chunk);
+
+  let result = OpenOptions::new()
+    .append(true)
+    .create(true)
+    .open(location)
+    .and_then(|mut file| {
+      file.write_all(&package)?;
+      file.flush()?;
+      return Ok(());
+    });
+
+  if result.is_err() {
+    return Err(Errors::FileIOError("Failed to write chunk to file".to_string()));
+  }
+
+  return Ok(());
+}
+
+/// Reads the record headers of the spool file without loading
+/// the chunks themselves. A trailing record that was only partially
+/// written is ignored.
+pub fn index(location: &str) -> Result<Vec<Record>, Errors> {
+  let file = match File::open(location) {
+    Ok(f) => f,
+    Err(_) => return Err(Errors::FileIOError("Failed to open spool file".to_string()))
+  };
+  let spool_length = match file.metadata() {
+    Ok(m) => m.len(),
+    Err(_) => return Err(Errors::FileIOError("Failed to read spool metadata".to_string()))
+  };
+
+  let mut reader = BufReader::new(file);
+  let mut records = Vec::new();
+  let mut offset: u64 = 0;
+
+  while offset + RECORD_HEADER_BYTES <= spool_length {
+    let mut header = vec![0u8; RECORD_HEADER_BYTES as usize];
+    if reader.read_exact(&mut header).is_err() {
+      return Err(Errors::FileIOError("Failed to read spool record".to_string()));
+    }
+
+    let chunk_num = util::read_u32(&header[0..4].to_vec())?;
+    let length = util::read_u32(&header[4..8].to_vec())?;
+    let chunk_offset = offset + RECORD_HEADER_BYTES;
+
+    if chunk_offset + length as u64 > spool_length {
+      break;
+    }
+
+    if reader.seek(SeekFrom::Current(length as i64)).is_err() {
+      return Err(Errors::FileIOError("Failed to seek spool record".to_string()));
+    }
+
+    records.push(Record { chunk_num, length, offset: chunk_offset });
+    offset = chunk_offset + length as u64;
+  }
+
+  return Ok(records);
+}
+
+/// Deletes the spool file, returning how many chunk bytes it
+/// held. A spool that doesn't exist has nothing to discard.
+pub fn discard(location: &str) -> Result<u64, Errors> {
+  if !Path::new(location).exists() {
+    return Ok(0);
+  }
+
+  let discarded = index(location)?.iter().map(|r| r.length as u64).sum();
+
+  if std::fs::remove_file(location).is_err() {
+    return Err(Errors::FileIOError("Failed to delete spool file".to_string()));
+  }
+
+  return Ok(discarded);
+}
+
+/// Whether the file name is one `location` gives a spool, or
+/// the .part file a spool is assembled into.
+pub fn is_spool_name(file_name: &str) -> bool {
+  return match (file_name.get(..36), file_name.get(36..37)) {
+    (Some(lease_id), Some("_")) => Uuid::parse_str(lease_id).is_ok() && file_name.len() > 37,
+    _ => false
+  };
+}
+
+/// Deletes spools in the incoming directory that aren't one of
+/// the kept ones, like the spools of leases lost in a restart or
+/// files left half assembled. Files not named like a spool are
+/// left alone. Returns how many went.
+pub fn sweep(incoming_dir: &Path, keep: &HashSet<String>) -> usize {
+  let entries = match std::fs::read_dir(incoming_dir) {
+    Ok(e) => e,
+    Err(_) => return 0
+  };
+
+  let mut swept = 0;
+  for entry in entries.flatten() {
+    let location = entry.path().to_string_lossy().to_string();
+    let spool = is_spool_name(&entry.file_name().to_string_lossy());
+    if spool && entry.path().is_file() && !keep.contains(&location) && std::fs::remove_file(&location).is_ok() {
+      swept += 1;
+    }
+  }
+
+  return swept;
+}
+
+/// Writes the chunks of the spool file, ordered by chunk_num,
+/// into one contiguous file at the output location.
+pub fn assemble(location: &str, output: &str, file_length: u32) -> Result<(), Errors> {
+  let mut records = index(location)?;
+  records.sort_by_key(|r| r.chunk_num);
+  records.dedup_by_key(|r| r.chunk_num);
+
+  let total: u64 = records.iter().map(|r| r.length as u64).sum();
+  if total != file_length as u64 {
+    return Err(Errors::FileIOError("Spool does not contain the entire file".to_string()));
+  }
+
+  let result = File::open(location).and_then(|mut spool| {
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    for record in records.iter() {
+      spool.seek(SeekFrom::Start(record.offset))?;
+      let copied = std::io::copy(&mut (&mut spool).take(record.length as u64), &mut writer)?;
+      if copied != record.length as u64 {
+        return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "truncated spool record"));
+      }
+    }
+
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    return Ok(());
+  });
+
+  if result.is_err() {
+    std::fs::remove_file(output).ok();
+    return Err(Errors::FileIOError("Failed to assemble file from spool".to_string()));
+  }
+
+  return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn assembles_out_of_order_chunks() {
+    let dir = std::env::temp_dir();
+    let spool = dir.join("rjchunker_spool_test").to_string_lossy().to_string();
+    let output = dir.join("rjchunker_spool_test_out").to_string_lossy().to_string();
+    std::fs::remove_file(&spool).ok();
+
+    append(&spool, &2, &2, b"ef").unwrap();
+    append(&spool, &0, &2, b"ab").unwrap();
+    append(&spool, &1, &2, b"cd").unwrap();
+
+    assemble(&spool, &output, 6).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), b"abcdef");
+    assert!(assemble(&spool, &output, 8).is_err());
+
+    assert_eq!(discard(&spool).unwrap(), 6);
+    assert_eq!(discard(&spool).unwrap(), 0);
+    std::fs::remove_file(&output).ok();
+  }
+
+  #[test]
+  fn sweeps_only_spools() {
+    let dir = std::env::temp_dir().join("rjchunker_sweep_test");
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let kept = location(&dir, "9b2f4c1e-58a7-4d3b-a1e6-0c7d2e5f8a90", "kept.bin");
+    let orphan = location(&dir, "1d3e5f7a-9b0c-4d2e-8f1a-3b5c7d9e0f21", "orphan.bin");
+    let other = dir.join("leases.journal").to_string_lossy().to_string();
+    for file in [&kept, &orphan, &other].iter() {
+      std::fs::write(file, b"data").unwrap();
+    }
+
+    let keep: HashSet<String> = [kept.to_string()].iter().cloned().collect();
+    assert_eq!(sweep(&dir, &keep), 1);
+    assert!(Path::new(&kept).exists());
+    assert!(!Path::new(&orphan).exists());
+    assert!(Path::new(&other).exists());
+
+    assert!(is_spool_name("9b2f4c1e-58a7-4d3b-a1e6-0c7d2e5f8a90_a.bin.part"));
+    assert!(!is_spool_name("9b2f4c1e-58a7-4d3b-a1e6-0c7d2e5f8a90_"));
+    assert!(!is_spool_name("photo.jpg"));
+    std::fs::remove_dir_all(&dir).ok();
+  }
+}
@@ -0,0 +1,287 @@
+use std::env;
+use std::io::{self, Write};
+use std::process;
+use std::collections::HashMap;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use rjchunker::spawn_server;
+use rjchunker::config::ServerConfig;
+use rjchunker::client::{self, Uploader};
+use rjchunker::errors::Errors;
+use rjchunker::io::write::error_message;
+
+const USAGE: &str = "usage: rjchunker <command> [options]
+
+commands:
+  serve                   run a server until it's killed
+  put <file>              upload a file
+  resume <lease> <file>   send the chunks the lease is missing
+  status <lease>          show what the lease has received
+  cancel <lease>          drop the lease and what it received
+
+serve options:
+  --config <file>         TOML file of config keys
+  --bind <address>        address to listen on
+
+client options:
+  --server <address>      server to connect to (default 127.0.0.1:7878)
+  --chunk-bytes <n>       length of each chunk (default 64000)
+  --connections <n>       chunks sent at once (default 1)
+  --quiet                 no progress output
+
+Exits 0 when done and 1 on usage or local errors. When the server
+turns a request down, exits with its response byte, e.g. 5 for
+NO_LEASE, 7 for CHECKSUM_MISMATCH or 10 for FILE_EXISTS.";
+
+const DEFAULT_SERVER: &str = "127.0.0.1:7878";
+
+/// Options that are set without a value.
+const FLAGS: [&str; 1] = ["quiet"];
+
+/// Writes what the server logs to stderr while serving.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    return metadata.level() <= Level::Info;
+  }
+
+  fn log(&self, record: &Record) {
+    if self.enabled(record.metadata()) {
+      eprintln!("rjchunker: {}", record.args());
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Command line split into positional arguments and --options.
+struct Args {
+  positional: Vec<String>,
+  options: HashMap<String, String>
+}
+
+impl Args {
+  fn parse(args: Vec<String>) -> Result<Args, Errors> {
+    let mut positional = Vec::new();
+    let mut options = HashMap::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+      let name = match arg.strip_prefix("--") {
+        Some(n) => n.to_string(),
+        None => {
+          positional.push(arg);
+          continue;
+        }
+      };
+
+      if FLAGS.contains(&name.as_str()) {
+        options.insert(name, String::new());
+        continue;
+      }
+
+      match args.next() {
+        Some(value) => options.insert(name, value),
+        None => return Err(Errors::InvalidRequest(format!("--{} needs a value", name)))
+      };
+    }
+
+    return Ok(Args { positional, options });
+  }
+
+  fn positional(&self, index: usize, name: &str) -> Result<&str, Errors> {
+    return match self.positional.get(index) {
+      Some(p) => Ok(p),
+      None => Err(Errors::InvalidRequest(format!("Missing <{}>", name)))
+    };
+  }
+
+  fn option(&self, name: &str) -> Option<&str> {
+    return self.options.get(name).map(|o| o.as_str());
+  }
+
+  fn number<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, Errors> {
+    return match self.option(name) {
+      Some(value) => match value.parse::<T>() {
+        Ok(n) => Ok(Some(n)),
+        Err(_) => Err(Errors::InvalidRequest(format!("--{} must be a number", name)))
+      },
+      None => Ok(None)
+    };
+  }
+
+  /// Errors on options the command doesn't take.
+  fn allow(&self, names: &[&str]) -> Result<(), Errors> {
+    for name in self.options.keys() {
+      if !names.contains(&name.as_str()) {
+        return Err(Errors::InvalidRequest(format!("Unknown option --{}", name)));
+      }
+    }
+
+    return Ok(());
+  }
+}
+
+fn main() {
+  let args: Vec<String> = env::args().skip(1).collect();
+
+  if args.is_empty() || args[0] == "help" || args[0] == "--help" {
+    println!("{}", USAGE);
+    return;
+  }
+
+  let code = match Args::parse(args).and_then(|a| run(&a)) {
+    Ok(()) => 0,
+    Err(e) => {
+      if let Errors::InvalidRequest(message) = &e {
+        eprintln!("rjchunker: {}\n\n{}", message, USAGE);
+      } else {
+        eprintln!("rjchunker: {:?}", e);
+      }
+      exit_code(&e)
+    }
+  };
+
+  process::exit(code);
+}
+
+fn run(args: &Args) -> Result<(), Errors> {
+  let client_options = ["server", "chunk-bytes", "connections", "quiet"];
+
+  return match args.positional(0, "command")? {
+    "serve" => {
+      args.allow(&["config", "bind"])?;
+      serve(args)
+    },
+    "put" => {
+      args.allow(&client_options)?;
+      put(args)
+    },
+    "resume" => {
+      args.allow(&client_options)?;
+      resume(args)
+    },
+    "status" => {
+      args.allow(&["server"])?;
+      status(args)
+    },
+    "cancel" => {
+      args.allow(&["server"])?;
+      cancel(args)
+    },
+    command => Err(Errors::InvalidRequest(format!("Unknown command {}", command)))
+  };
+}
+
+/// Refusals exit with the response byte the server sent.
+/// Anything else went wrong on this end.
+fn exit_code(error: &Errors) -> i32 {
+  return match error {
+    Errors::BusyError(_)
+    | Errors::ChecksumError(_)
+    | Errors::OutOfRangeError(_)
+    | Errors::NoLeaseError(_)
+    | Errors::LeaseInUseError(_)
+    | Errors::FileExistsError(_)
+    | Errors::ResponseError(_) => error_message(error)[0] as i32,
+    _ => 1
+  };
+}
+
+/// Config comes from the file, then the environment,
+/// then the options on the command line.
+fn serve(args: &Args) -> Result<(), Errors> {
+  let mut builder = ServerConfig::builder();
+  if let Some(location) = args.option("config") {
+    builder = builder.toml_file(location)?;
+  }
+  builder = builder.env()?;
+  if let Some(bind_address) = args.option("bind") {
+    builder = builder.bind_address(bind_address);
+  }
+
+  if log::set_logger(&LOGGER).is_ok() {
+    log::set_max_level(LevelFilter::Info);
+  }
+
+  let handle = spawn_server(builder.build()?)?;
+  eprintln!("rjchunker: listening on {}", handle.local_addr());
+  handle.wait();
+
+  return Ok(());
+}
+
+fn put(args: &Args) -> Result<(), Errors> {
+  let location = args.positional(1, "file")?;
+
+  let upload = uploader(args)?.upload_file(location);
+  end_progress(args);
+  let upload = upload?;
+
+  println!("{} {} bytes in {} chunks as {}", upload.file_name, upload.file_length, upload.chunks, upload.lease_id);
+  return Ok(());
+}
+
+fn resume(args: &Args) -> Result<(), Errors> {
+  let lease_id = args.positional(1, "lease")?;
+  let location = args.positional(2, "file")?;
+
+  let upload = uploader(args)?.resume_file(lease_id, location);
+  end_progress(args);
+  let upload = upload?;
+
+  println!("{} {} bytes in {} chunks as {}", upload.file_name, upload.file_length, upload.chunks, upload.lease_id);
+  return Ok(());
+}
+
+fn status(args: &Args) -> Result<(), Errors> {
+  let lease_id = args.positional(1, "lease")?;
+  let status = uploader(args)?.status(lease_id)?;
+
+  let received: Vec<String> = status.ranges.iter()
+    .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+    .collect();
+
+  println!("lease {}", lease_id);
+  println!("bytes_left {}", status.bytes_left);
+  println!("chunks_sent {}", status.chunks_sent);
+  println!("received {}", received.join(","));
+  return Ok(());
+}
+
+fn cancel(args: &Args) -> Result<(), Errors> {
+  let lease_id = args.positional(1, "lease")?;
+  let discarded = uploader(args)?.cancel(lease_id)?;
+
+  println!("discarded {} bytes", discarded);
+  return Ok(());
+}
+
+fn uploader(args: &Args) -> Result<Uploader, Errors> {
+  let mut uploader = Uploader::new(args.option("server").unwrap_or(DEFAULT_SERVER))
+    .chunk_bytes(args.number("chunk-bytes")?.unwrap_or(client::DEFAULT_CHUNK_BYTES))
+    .connections(args.number("connections")?.unwrap_or(client::DEFAULT_CONNECTIONS));
+
+  if args.option("quiet").is_none() {
+    uploader = uploader.progress(print_progress);
+  }
+
+  return Ok(uploader);
+}
+
+fn print_progress(lease_id: &str, sent: u32, file_length: u32) {
+  let percent = sent as u64 * 100 / (file_length as u64).max(1);
+  eprint!("\r{} {}/{} bytes ({}%)", lease_id, sent, file_length, percent);
+  io::stderr().flush().ok();
+}
+
+/// Moves past the progress line, if there was one.
+fn end_progress(args: &Args) {
+  if args.option("quiet").is_none() {
+    eprintln!();
+  }
+}
@@ -0,0 +1,246 @@
+use std::env;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, info, warn, error};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use rjchunker::spawn_server;
+use rjchunker::config::ServerConfig;
+use rjchunker::errors::Errors;
+
+const USAGE: &str = "usage: rjchunkerd [options]
+
+options:
+  --config <file>         TOML file of config keys. RJCHUNKER_ environment
+                          variables override it.
+  --pid-file <file>       where the pid is written (default rjchunkerd.pid
+                          in the storage_dir)
+  --drain-timeout <secs>  how long to let accepted uploads finish on
+                          SIGTERM or SIGINT (default 30)
+
+SIGHUP rereads the config and takes on its limits: max_leases,
+max_queued_bytes, max_in_flight_bytes, retry_after_ms and
+max_lease_writers. Other keys take effect on restart. A second
+SIGTERM or SIGINT stops without waiting for the drain.
+
+Won't start while another rjchunkerd holds the pid file.";
+
+/// Every option takes a value.
+const OPTIONS: [&str; 3] = ["--config", "--pid-file", "--drain-timeout"];
+
+const DEFAULT_PID_FILE: &str = "rjchunkerd.pid";
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// Priorities of the syslog levels, as journald and
+/// other collectors read them off of line prefixes.
+const LOG_ERR: u8 = 3;
+const LOG_WARNING: u8 = 4;
+const LOG_INFO: u8 = 6;
+const LOG_DEBUG: u8 = 7;
+
+/// Writes the daemon's and the library's records to stderr,
+/// one line each with the syslog priority up front.
+struct SyslogLogger;
+
+impl Log for SyslogLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    return metadata.level() <= Level::Info;
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+
+    let priority = match record.level() {
+      Level::Error => LOG_ERR,
+      Level::Warn => LOG_WARNING,
+      Level::Info => LOG_INFO,
+      _ => LOG_DEBUG
+    };
+    eprintln!("<{}>rjchunkerd[{}]: {}", priority, process::id(), record.args());
+  }
+
+  fn flush(&self) {}
+}
+
+static LOGGER: SyslogLogger = SyslogLogger;
+
+struct Options {
+  config: Option<PathBuf>,
+  pid_file: Option<PathBuf>,
+  drain_timeout: Duration
+}
+
+fn main() {
+  let options = match parse(env::args().skip(1).collect()) {
+    Ok(o) => o,
+    Err(message) => {
+      eprintln!("rjchunkerd: {}\n\n{}", message, USAGE);
+      process::exit(1);
+    }
+  };
+
+  if log::set_logger(&LOGGER).is_ok() {
+    log::set_max_level(LevelFilter::Info);
+  }
+
+  if let Err(e) = run(&options) {
+    error!("{:?}", e);
+    process::exit(1);
+  }
+}
+
+fn parse(args: Vec<String>) -> Result<Options, String> {
+  let mut options = Options {
+    config: None,
+    pid_file: None,
+    drain_timeout: Duration::from_secs(DEFAULT_DRAIN_TIMEOUT_SECS)
+  };
+  let mut args = args.into_iter();
+
+  while let Some(arg) = args.next() {
+    if arg == "--help" {
+      println!("{}", USAGE);
+      process::exit(0);
+    }
+
+    if !OPTIONS.contains(&arg.as_str()) {
+      return Err(format!("Unknown option {}", arg));
+    }
+
+    let value = match args.next() {
+      Some(v) => v,
+      None => return Err(format!("{} needs a value", arg))
+    };
+
+    match arg.as_str() {
+      "--config" => options.config = Some(PathBuf::from(value)),
+      "--pid-file" => options.pid_file = Some(PathBuf::from(value)),
+      "--drain-timeout" => options.drain_timeout = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => return Err("--drain-timeout must be a number of seconds".to_string())
+      },
+      _ => unreachable!()
+    };
+  }
+
+  return Ok(options);
+}
+
+/// Config from the file, with the environment on top.
+fn load_config(location: &Option<PathBuf>) -> Result<ServerConfig, Errors> {
+  let mut builder = ServerConfig::builder();
+  if let Some(location) = location {
+    builder = builder.toml_file(location)?;
+  }
+
+  return builder.env()?.build();
+}
+
+/// Serves until SIGTERM or SIGINT, then drains and exits.
+fn run(options: &Options) -> Result<(), Errors> {
+  let config = load_config(&options.config)?;
+  let pid_file = options.pid_file.clone()
+    .unwrap_or_else(|| config.storage_dir.join(DEFAULT_PID_FILE));
+
+  // registered before the server starts, so a signal
+  // never lands on the default handler and kills it
+  let mut signals = match Signals::new([SIGTERM, SIGINT, SIGHUP]) {
+    Ok(s) => s,
+    Err(e) => return Err(Errors::UnexpectedError(format!("Failed to register signal handlers: {}", e)))
+  };
+
+  // held until the process exits, which is what
+  // tells another instance this one is running
+  let _lock = lock_pid_file(&pid_file)?;
+  let handle = match spawn_server(config) {
+    Ok(h) => h,
+    Err(e) => {
+      fs::remove_file(&pid_file).ok();
+      return Err(e);
+    }
+  };
+  info!("listening on {} as pid {}", handle.local_addr(), process::id());
+
+  for signal in signals.forever() {
+    if signal == SIGHUP {
+      match load_config(&options.config) {
+        Ok(config) => {
+          handle.reload(&config);
+          info!(
+            "reloaded limits: max_leases={} max_queued_bytes={} max_in_flight_bytes={} retry_after_ms={} max_lease_writers={}",
+            config.max_leases, config.max_queued_bytes, config.max_in_flight_bytes,
+            config.retry_after.as_millis(), config.max_lease_writers
+          );
+        },
+        Err(e) => warn!("kept the running limits, reload failed: {:?}", e)
+      };
+      continue;
+    }
+
+    info!("draining for up to {}s", options.drain_timeout.as_secs());
+    break;
+  }
+
+  // the drain is cut short by another SIGTERM or SIGINT
+  let forced_pid_file = pid_file.clone();
+  thread::spawn(move || {
+    for signal in signals.forever() {
+      if signal != SIGHUP {
+        warn!("stopped before the drain finished");
+        fs::remove_file(&forced_pid_file).ok();
+        process::exit(1);
+      }
+    }
+  });
+
+  let result = handle.shutdown(options.drain_timeout);
+  fs::remove_file(&pid_file).ok();
+
+  if result.is_ok() {
+    info!("stopped");
+  }
+
+  return result;
+}
+
+/// Locks the pid file and writes the pid into it. Fails if
+/// another instance holds the lock. A file left by one that
+/// died isn't locked, so it's taken over. Its directory is
+/// created first, as on a fresh install nothing else has yet.
+fn lock_pid_file(location: &Path) -> Result<File, Errors> {
+  if let Some(dir) = location.parent() {
+    if let Err(e) = fs::create_dir_all(dir) {
+      return Err(Errors::FileIOError(format!("Failed to create {:?}: {}", dir, e)));
+    }
+  }
+
+  let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(location) {
+    Ok(f) => f,
+    Err(e) => return Err(Errors::FileIOError(format!("Failed to open pid file {:?}: {}", location, e)))
+  };
+
+  match file.try_lock() {
+    Ok(()) => (),
+    Err(TryLockError::WouldBlock) => {
+      let pid = fs::read_to_string(location).unwrap_or_default();
+      return Err(Errors::UnexpectedError(format!("Already running as pid {}, per {:?}", pid.trim(), location)));
+    },
+    Err(TryLockError::Error(e)) => return Err(Errors::FileIOError(format!("Failed to lock pid file {:?}: {}", location, e)))
+  };
+
+  let written = file.set_len(0)
+    .and_then(|_| file.write_all(format!("{}\n", process::id()).as_bytes()));
+  if let Err(e) = written {
+    return Err(Errors::FileIOError(format!("Failed to write pid file {:?}: {}", location, e)));
+  }
+
+  return Ok(file);
+}
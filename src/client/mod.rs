@@ -0,0 +1,632 @@
+use std::io::{Read, Write, Cursor};
+use std::fs::{self, File};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use crossbeam_channel::bounded;
+
+use crate::errors::Errors;
+use crate::headers::{Headers, HeaderType};
+use crate::headers::write::write_headers;
+use crate::assembler::checksum::{self, Algorithm};
+use crate::io::{read, util};
+
+pub mod response;
+
+use crate::client::response::{Response, LeaseStatus, STATUS_BYTES, read_response, read_status};
+
+pub const DEFAULT_CHUNK_BYTES: u32 = 64000; // 64 KB
+pub const DEFAULT_RETRIES: u32 = 5;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_CONNECTIONS: usize = 1;
+/// wait between retries when the server doesn't say how long
+pub const RETRY_BACKOFF_MS: u64 = 250;
+/// slowest rate an upload is expected to be assembled and
+/// hashed at, while the chunk that completes it waits
+pub const FINALIZE_BYTES_PER_SEC: u64 = 10_000_000; // 10 MB
+
+/// Uploads files to a server, one connection per request.
+#[derive(Debug, Clone)]
+pub struct Uploader {
+  address: String,
+  chunk_bytes: u32,
+  retries: u32,
+  timeout: Duration,
+  connections: usize,
+  progress: Option<fn(&str, u32, u32)>
+}
+
+/// A finished upload.
+#[derive(Debug, Clone)]
+pub struct Upload {
+  pub lease_id: String,
+  pub file_name: String,
+  pub file_length: u32,
+  pub chunks: u32
+}
+
+impl Uploader {
+  pub fn new<S: Into<String>>(address: S) -> Uploader {
+    return Uploader {
+      address: address.into(),
+      chunk_bytes: DEFAULT_CHUNK_BYTES,
+      retries: DEFAULT_RETRIES,
+      timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+      connections: DEFAULT_CONNECTIONS,
+      progress: None
+    };
+  }
+
+  /// Length of every chunk but the last. Has to be within
+  /// the chunk bounds the server is configured with.
+  pub fn chunk_bytes(mut self, chunk_bytes: u32) -> Uploader {
+    self.chunk_bytes = chunk_bytes.max(1);
+    return self;
+  }
+
+  /// Times a request is resent when the server
+  /// answers RETRY or LEASE_IN_USE.
+  pub fn retries(mut self, retries: u32) -> Uploader {
+    self.retries = retries;
+    return self;
+  }
+
+  /// How long reads and writes on a connection may block.
+  pub fn timeout(mut self, timeout: Duration) -> Uploader {
+    self.timeout = timeout;
+    return self;
+  }
+
+  /// Chunks sent at once, each over its own connection. More
+  /// than the server's max_lease_writers are answered
+  /// LEASE_IN_USE and resent.
+  pub fn connections(mut self, connections: usize) -> Uploader {
+    self.connections = connections.max(1);
+    return self;
+  }
+
+  /// Called with the lease_id, the bytes the server has taken
+  /// so far and the file length, each time a chunk is sent.
+  pub fn progress(mut self, progress: fn(&str, u32, u32)) -> Uploader {
+    self.progress = Some(progress);
+    return self;
+  }
+
+  /// Uploads the file under its own file name. The file is read
+  /// twice, once for its checksum and again to send the chunks.
+  pub fn upload_file<P: AsRef<Path>>(&self, location: P) -> Result<Upload, Errors> {
+    let location = location.as_ref();
+    let (file_name, file_length) = file_info(location)?;
+
+    let digest = checksum::digest_file(&location.to_string_lossy(), Algorithm::SHA256)?;
+    let file = open_file(location)?;
+
+    return self.send(file, &file_name, file_length, &format!("sha256:{}", digest));
+  }
+
+  /// Sends the chunks the lease is missing from the file it
+  /// was leased for. chunk_bytes has to be what the upload
+  /// started with, so each chunk_num is the same bytes; a
+  /// status that doesn't fit the file is refused up front.
+  pub fn resume_file<P: AsRef<Path>>(&self, lease_id: &str, location: P) -> Result<Upload, Errors> {
+    let location = location.as_ref();
+    let (file_name, file_length) = file_info(location)?;
+
+    let status = self.lease_status(lease_id, self.chunk_count(file_length))?;
+    self.check_status(&status, lease_id, file_length)?;
+    if status.bytes_left == 0 {
+      // nothing left to send, only the finalize to wait out
+      self.await_finalize(lease_id)?;
+    } else {
+      let mut file = open_file(location)?;
+      self.send_chunks(&mut file, lease_id, file_length, 0, &status)?;
+    }
+
+    return Ok(Upload {
+      lease_id: lease_id.to_string(),
+      file_name,
+      file_length,
+      chunks: self.chunk_count(file_length)
+    });
+  }
+
+  /// What the lease has received, to work out what's left to send.
+  pub fn status(&self, lease_id: &str) -> Result<LeaseStatus, Errors> {
+    return self.lease_status(lease_id, u32::MAX);
+  }
+
+  /// `status` of a lease with at most max_ranges ranges.
+  fn lease_status(&self, lease_id: &str, max_ranges: u32) -> Result<LeaseStatus, Errors> {
+    let headers = Headers {
+      header_type: HeaderType::STATUS,
+      lease_id: Some(lease_id.to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: None,
+      chunk_num: None,
+      chunk_checksum: None,
+      cancel: None
+    };
+
+    let mut server = self.open(&frame(&headers, &[])?, self.timeout)?;
+    return match read_response(&mut server, STATUS_BYTES)? {
+      Response::Ok(head) => read_status(&head, &mut server, max_ranges),
+      response => Err(refused(response))
+    };
+  }
+
+  /// Polls the status of a lease that has every chunk until the
+  /// server finalizes it and drops the lease, or the timeout runs
+  /// out. A lease that fails its checksum is dropped the same way.
+  fn await_finalize(&self, lease_id: &str) -> Result<(), Errors> {
+    let started = std::time::Instant::now();
+
+    loop {
+      match self.status(lease_id) {
+        Ok(_) => (),
+        Err(Errors::NoLeaseError(_)) => return Ok(()),
+        Err(e) => return Err(e)
+      };
+
+      if started.elapsed() >= self.timeout {
+        return Err(Errors::LeaseInUseError("Lease has every chunk but wasn't finalized in time".to_string()));
+      }
+      thread::sleep(Duration::from_millis(RETRY_BACKOFF_MS));
+    }
+  }
+
+  /// Drops the lease and what it received. Responds with
+  /// the bytes the server discarded.
+  pub fn cancel(&self, lease_id: &str) -> Result<u64, Errors> {
+    let headers = Headers {
+      header_type: HeaderType::CANCEL,
+      lease_id: Some(lease_id.to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: None,
+      chunk_num: None,
+      chunk_checksum: None,
+      cancel: Some(true)
+    };
+
+    let discarded = self.request(&headers, &[], 8, self.timeout)?;
+    if discarded.len() != 8 {
+      return Err(Errors::ParseError("Cancel response is missing the discarded bytes".to_string()));
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&discarded);
+
+    return Ok(u64::from_le_bytes(bytes));
+  }
+
+  /// Uploads everything the reader has as file_name. The
+  /// reader is read into memory to work out its checksum.
+  pub fn upload<R: Read>(&self, mut reader: R, file_name: &str) -> Result<Upload, Errors> {
+    let mut data = Vec::new();
+    if reader.read_to_end(&mut data).is_err() {
+      return Err(Errors::ReadError("Failed to read the upload".to_string()));
+    }
+
+    if data.len() > u32::MAX as usize {
+      return Err(Errors::InvalidRequest("File is too large to upload".to_string()));
+    }
+
+    let digest = checksum::digest_bytes(&data, Algorithm::SHA256);
+    let file_length = data.len() as u32;
+
+    return self.send(Cursor::new(data), file_name, file_length, &format!("sha256:{}", digest));
+  }
+
+  /// Leases the file with the first chunk, then sends the rest
+  /// over the connections as they're read. The server answers
+  /// the chunk that completes the file once it's assembled and
+  /// matches the hash.
+  fn send<R: Read>(&self, mut reader: R, file_name: &str, file_length: u32, hash: &str) -> Result<Upload, Errors> {
+    if file_length == 0 {
+      return Err(Errors::InvalidRequest("Can't upload an empty file".to_string()));
+    }
+
+    let chunks = self.chunk_count(file_length);
+
+    let chunk = read_chunk(&mut reader, self.chunk_bytes.min(file_length))?;
+    let headers = Headers {
+      header_type: HeaderType::LEASE,
+      lease_id: None,
+      checksum: Some(hash.to_string()),
+      file_name: Some(file_name.to_string()),
+      file_length: Some(file_length),
+      chunk_length: Some(chunk.len() as u32),
+      chunk_num: Some(0),
+      chunk_checksum: Some(crc32c::crc32c(&chunk)),
+      cancel: None
+    };
+    let lease_id = util::read_uuid(&self.request(&headers, &chunk, 16, self.finalize_timeout(file_length))?)?;
+
+    self.report(&lease_id, chunk.len() as u32, file_length);
+
+    // what the lease holds now the first chunk is in
+    let status = LeaseStatus {
+      bytes_left: file_length - chunk.len() as u32,
+      chunks_sent: 1,
+      ranges: vec![(0, 0)]
+    };
+    self.send_chunks(&mut reader, &lease_id, file_length, 1, &status)?;
+
+    return Ok(Upload {
+      lease_id,
+      file_name: file_name.to_string(),
+      file_length,
+      chunks
+    });
+  }
+
+  /// Reads the chunks from first on into a queue only a few
+  /// chunks deep, which the connection threads send from. Chunks
+  /// the lease already has are read past. The first error stops
+  /// the reading and is returned once the threads finish.
+  fn send_chunks<R: Read>(&self, reader: &mut R, lease_id: &str, file_length: u32, first: u32, status: &LeaseStatus) -> Result<(), Errors> {
+    let (chunk_s, chunk_r) = bounded::<(u32, Vec<u8>)>(self.connections);
+    let failed = AtomicBool::new(false);
+    let sent = AtomicU32::new(file_length.saturating_sub(status.bytes_left));
+    let chunks = self.chunk_count(file_length);
+    let mut bytes_left = file_length.saturating_sub(first.saturating_mul(self.chunk_bytes));
+
+    return thread::scope(|scope| {
+      let senders: Vec<_> = (0..self.connections).map(|_| {
+        let chunk_r = chunk_r.clone();
+        let failed = &failed;
+        let sent = &sent;
+        return scope.spawn(move || -> Result<(), Errors> {
+          for (chunk_num, chunk) in chunk_r.iter() {
+            if let Err(e) = self.send_chunk(lease_id, chunk_num, &chunk, file_length) {
+              failed.store(true, Ordering::SeqCst);
+              return Err(e);
+            }
+            let length = chunk.len() as u32;
+            self.report(lease_id, sent.fetch_add(length, Ordering::SeqCst) + length, file_length);
+          }
+          return Ok(());
+        });
+      }).collect();
+      drop(chunk_r);
+
+      let mut result = Ok(());
+      for chunk_num in first..chunks {
+        if failed.load(Ordering::SeqCst) {
+          break;
+        }
+
+        let chunk = match read_chunk(reader, self.chunk_bytes.min(bytes_left)) {
+          Ok(c) => c,
+          Err(e) => {
+            result = Err(e);
+            break;
+          }
+        };
+        bytes_left -= chunk.len() as u32;
+
+        if status.has_chunk(chunk_num) {
+          continue;
+        }
+
+        // every sender has quit, so one has the error
+        if chunk_s.send((chunk_num, chunk)).is_err() {
+          break;
+        }
+      }
+      drop(chunk_s);
+
+      for sender in senders {
+        let sent = match sender.join() {
+          Ok(r) => r,
+          Err(_) => Err(Errors::UnexpectedError("Chunk sender panicked".to_string()))
+        };
+        if result.is_ok() {
+          result = sent;
+        }
+      }
+
+      return result;
+    });
+  }
+
+  fn send_chunk(&self, lease_id: &str, chunk_num: u32, chunk: &[u8], file_length: u32) -> Result<(), Errors> {
+    let headers = Headers {
+      header_type: HeaderType::CHUNK,
+      lease_id: Some(lease_id.to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: Some(chunk.len() as u32),
+      chunk_num: Some(chunk_num),
+      chunk_checksum: Some(crc32c::crc32c(chunk)),
+      cancel: None
+    };
+    self.request(&headers, chunk, 0, self.finalize_timeout(file_length))?;
+
+    return Ok(());
+  }
+
+  /// Any chunk could be the one that completes the file, and
+  /// that one isn't answered until the file is finalized.
+  fn finalize_timeout(&self, file_length: u32) -> Duration {
+    return self.timeout + Duration::from_secs(file_length as u64 / FINALIZE_BYTES_PER_SEC);
+  }
+
+  /// Refuses a lease whose status couldn't come from this file in
+  /// chunks of chunk_bytes: a chunk_num past its end, or bytes_left
+  /// other than what the received chunks leave of its length.
+  fn check_status(&self, status: &LeaseStatus, lease_id: &str, file_length: u32) -> Result<(), Errors> {
+    let chunks = self.chunk_count(file_length) as u64;
+    let chunk_bytes = self.chunk_bytes as u64;
+    let last_length = file_length as u64 - (chunks - 1) * chunk_bytes;
+
+    let mut received = 0;
+    for (start, end) in &status.ranges {
+      let (start, end) = (*start as u64, *end as u64);
+      if start > end || end >= chunks {
+        return Err(Errors::ChecksumError(format!("Lease {} has chunk {} of a file in {} chunks", lease_id, end, chunks)));
+      }
+      received += (end - start + 1) * chunk_bytes;
+      if end == chunks - 1 {
+        received -= chunk_bytes - last_length;
+      }
+    }
+
+    if received > file_length as u64 || status.bytes_left as u64 != file_length as u64 - received {
+      return Err(Errors::ChecksumError(format!("Lease {} has {} bytes left, not what the file leaves", lease_id, status.bytes_left)));
+    }
+
+    return Ok(());
+  }
+
+  fn chunk_count(&self, file_length: u32) -> u32 {
+    return file_length.saturating_sub(1) / self.chunk_bytes + 1;
+  }
+
+  fn report(&self, lease_id: &str, sent: u32, file_length: u32) {
+    if let Some(progress) = self.progress {
+      progress(lease_id, sent, file_length);
+    }
+  }
+
+  /// Sends the request until the server takes it, waiting
+  /// between attempts as long as the server asks to.
+  fn request(&self, headers: &Headers, body: &[u8], ok_length: usize, timeout: Duration) -> Result<Vec<u8>, Errors> {
+    let frame = frame(headers, body)?;
+
+    let mut attempts = 0;
+    loop {
+      attempts += 1;
+
+      let wait = match self.exchange(&frame, ok_length, timeout)? {
+        Response::Ok(payload) => return Ok(payload),
+        // a resend of a chunk the server already has
+        Response::DuplicateChunk => return Ok(Vec::new()),
+        Response::Retry(hint) => {
+          if attempts > self.retries {
+            return Err(Errors::BusyError(hint));
+          }
+          (hint.retry_after_ms as u64).max(RETRY_BACKOFF_MS * attempts as u64)
+        },
+        Response::LeaseInUse => {
+          if attempts > self.retries {
+            return Err(refused(Response::LeaseInUse));
+          }
+          RETRY_BACKOFF_MS * attempts as u64
+        },
+        response => return Err(refused(response))
+      };
+
+      thread::sleep(Duration::from_millis(wait));
+    }
+  }
+
+  fn exchange(&self, frame: &[u8], ok_length: usize, timeout: Duration) -> Result<Response, Errors> {
+    let mut server = self.open(frame, timeout)?;
+    return read_response(&mut server, ok_length);
+  }
+
+  /// Connects and sends the frame, leaving the response to be
+  /// read for up to the timeout.
+  fn open(&self, frame: &[u8], timeout: Duration) -> Result<TcpStream, Errors> {
+    let mut server = match TcpStream::connect(&self.address) {
+      Ok(s) => s,
+      Err(e) => return Err(Errors::UnexpectedError(format!("Failed to connect to {}: {}", self.address, e)))
+    };
+
+    let timeouts = server.set_read_timeout(Some(timeout))
+      .and_then(|_| server.set_write_timeout(Some(self.timeout)));
+    if timeouts.is_err() {
+      return Err(Errors::UnexpectedError("Failed to set connection timeouts".to_string()));
+    }
+
+    if server.write_all(frame).is_err() {
+      return Err(Errors::WriteError("Failed to send request".to_string()));
+    }
+
+    return Ok(server);
+  }
+}
+
+/// The error for a response that turns the request down.
+fn refused(response: Response) -> Errors {
+  return match response {
+    Response::Retry(hint) => Errors::BusyError(hint),
+    Response::LeaseInUse => Errors::LeaseInUseError("Lease is still in use".to_string()),
+    Response::ChecksumMismatch => Errors::ChecksumError("Uploaded file doesn't match its checksum".to_string()),
+    Response::OutOfRange => Errors::OutOfRangeError("Chunk is outside of the file".to_string()),
+    Response::NoLease => Errors::NoLeaseError("Server has no lease for the upload".to_string()),
+    Response::FileExists => Errors::FileExistsError("Server already has a file of the same name".to_string()),
+    _ => Errors::ResponseError("Server refused the request".to_string())
+  };
+}
+
+fn frame(headers: &Headers, body: &[u8]) -> Result<Vec<u8>, Errors> {
+  let mut frame = Vec::with_capacity(body.len() + 512);
+  write_headers(headers, &mut frame)?;
+  frame.extend_from_slice(body);
+
+  return Ok(frame);
+}
+
+/// File name and length of a file that can be uploaded.
+fn file_info(location: &Path) -> Result<(String, u32), Errors> {
+  let file_name = match location.file_name() {
+    Some(n) => n.to_string_lossy().to_string(),
+    None => return Err(Errors::InvalidRequest(format!("{:?} isn't a file", location)))
+  };
+
+  let file_length = match fs::metadata(location) {
+    Ok(m) if m.len() <= u32::MAX as u64 => m.len() as u32,
+    Ok(_) => return Err(Errors::InvalidRequest("File is too large to upload".to_string())),
+    Err(_) => return Err(Errors::FileIOError(format!("Failed to read {:?}", location)))
+  };
+
+  return Ok((file_name, file_length));
+}
+
+fn open_file(location: &Path) -> Result<File, Errors> {
+  return match File::open(location) {
+    Ok(f) => Ok(f),
+    Err(_) => Err(Errors::FileIOError(format!("Failed to open {:?}", location)))
+  };
+}
+
+fn read_chunk<R: Read>(reader: &mut R, chunk_length: u32) -> Result<Vec<u8>, Errors> {
+  return match read::read_exact(reader, chunk_length as usize) {
+    Ok(c) => Ok(c),
+    Err(_) => Err(Errors::ReadError("Upload ended before its length".to_string()))
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::PathBuf;
+  use crate::spawn_server;
+  use crate::config::{ServerConfig, ServerConfigBuilder};
+  use crate::handle::ServerHandle;
+
+  /// A server on an ephemeral port storing into a fresh temp
+  /// dir, and an uploader of 1000 byte chunks pointed at it.
+  struct Fixture {
+    storage_dir: PathBuf,
+    handle: ServerHandle,
+    uploader: Uploader
+  }
+
+  impl Fixture {
+    fn start(name: &str, builder: ServerConfigBuilder) -> Fixture {
+      let storage_dir = std::env::temp_dir().join(name);
+      fs::remove_dir_all(&storage_dir).ok();
+
+      let config = builder
+        .bind_address("127.0.0.1:0")
+        .storage_dir(&storage_dir)
+        .build()
+        .unwrap();
+      let handle = spawn_server(config).unwrap();
+      let uploader = Uploader::new(handle.local_addr().to_string()).chunk_bytes(1000);
+
+      return Fixture { storage_dir, handle, uploader };
+    }
+
+    fn assert_uploaded(&self, file_name: &str, data: &[u8]) {
+      assert_eq!(fs::read(self.storage_dir.join("complete").join(file_name)).unwrap(), data);
+    }
+
+    fn stop(self) {
+      self.handle.shutdown(Duration::from_secs(5)).unwrap();
+      fs::remove_dir_all(&self.storage_dir).ok();
+    }
+  }
+
+  fn test_data(length: u32) -> Vec<u8> {
+    return (0..length).map(|i| (i % 251) as u8).collect();
+  }
+
+  /// Leases the data with only its first chunk sent.
+  fn lease_first_chunk(uploader: &Uploader, data: &[u8], file_name: &str) -> String {
+    let chunk = &data[..1000];
+    let headers = Headers {
+      header_type: HeaderType::LEASE,
+      lease_id: None,
+      checksum: Some(format!("sha256:{}", checksum::digest_bytes(data, Algorithm::SHA256))),
+      file_name: Some(file_name.to_string()),
+      file_length: Some(data.len() as u32),
+      chunk_length: Some(chunk.len() as u32),
+      chunk_num: Some(0),
+      chunk_checksum: Some(crc32c::crc32c(chunk)),
+      cancel: None
+    };
+
+    return util::read_uuid(&uploader.request(&headers, chunk, 16, uploader.timeout).unwrap()).unwrap();
+  }
+
+  #[test]
+  fn uploads_a_file_in_chunks() {
+    let fixture = Fixture::start("rjchunker_client_test", ServerConfig::builder());
+
+    let data = test_data(2500);
+    let upload = fixture.uploader.upload(&data[..], "client_test.bin").unwrap();
+
+    assert_eq!(upload.chunks, 3);
+    fixture.assert_uploaded("client_test.bin", &data);
+    fixture.stop();
+  }
+
+  #[test]
+  fn resumes_and_cancels_leases() {
+    let fixture = Fixture::start("rjchunker_client_resume_test", ServerConfig::builder());
+    let uploader = &fixture.uploader;
+
+    let data = test_data(3200);
+    let location = fixture.storage_dir.join("client_resume_test.bin");
+    fs::write(&location, &data).unwrap();
+
+    let lease_id = lease_first_chunk(uploader, &data, "client_resume_test.bin");
+    let status = uploader.status(&lease_id).unwrap();
+    assert_eq!(status.bytes_left, 2200);
+    assert_eq!(status.ranges, vec![(0, 0)]);
+
+    let upload = uploader.resume_file(&lease_id, &location).unwrap();
+    assert_eq!(upload.chunks, 4);
+    fixture.assert_uploaded("client_resume_test.bin", &data);
+    assert!(matches!(uploader.status(&lease_id), Err(Errors::NoLeaseError(_))));
+
+    let lease_id = lease_first_chunk(uploader, &data, "client_mismatch_test.bin");
+    let other = fixture.storage_dir.join("client_mismatch_test.bin");
+    fs::write(&other, &data[..3100]).unwrap();
+    assert!(matches!(uploader.resume_file(&lease_id, &other), Err(Errors::ChecksumError(_))));
+    assert!(matches!(uploader.clone().chunk_bytes(500).resume_file(&lease_id, &location), Err(Errors::ChecksumError(_))));
+    assert_eq!(uploader.status(&lease_id).unwrap().ranges, vec![(0, 0)]);
+    assert!(uploader.cancel(&lease_id).is_ok());
+
+    let lease_id = lease_first_chunk(uploader, &data, "client_cancel_test.bin");
+    assert!(uploader.cancel(&lease_id).unwrap() >= 1000);
+    assert!(matches!(uploader.status(&lease_id), Err(Errors::NoLeaseError(_))));
+
+    fixture.stop();
+  }
+
+  #[test]
+  fn uploads_chunks_over_several_connections() {
+    let fixture = Fixture::start("rjchunker_client_parallel_test", ServerConfig::builder().max_lease_writers(2));
+
+    let data = test_data(20500);
+    let upload = fixture.uploader.clone()
+      .connections(4)
+      .retries(20)
+      .upload(&data[..], "client_parallel_test.bin")
+      .unwrap();
+
+    assert_eq!(upload.chunks, 21);
+    fixture.assert_uploaded("client_parallel_test.bin", &data);
+    fixture.stop();
+  }
+}
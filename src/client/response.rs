@@ -0,0 +1,147 @@
+use std::io::Read;
+
+use crate::errors::Errors;
+use crate::admission::{RetryHint, RETRY_HINT_BYTES};
+use crate::io::{read, util};
+use crate::io::write::{
+  OK_MESSAGE, ERR_MESSAGE, CONTINUE_MESSAGE, RETRY_MESSAGE, NO_LEASE_MESSAGE,
+  LEASE_IN_USE_MESSAGE, CHECKSUM_MISMATCH_MESSAGE, DUPLICATE_CHUNK_MESSAGE, OUT_OF_RANGE_MESSAGE,
+  FILE_EXISTS_MESSAGE
+};
+
+/// bytes_left, chunks_sent and the count of ranges that
+/// start a STATUS response.
+pub const STATUS_BYTES: usize = 12;
+
+/// What the server answered a request with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+  /// with whatever followed the OK
+  Ok(Vec<u8>),
+  Err,
+  Continue,
+  Retry(RetryHint),
+  NoLease,
+  LeaseInUse,
+  ChecksumMismatch,
+  DuplicateChunk,
+  OutOfRange,
+  FileExists,
+}
+
+/// Reads a response off of the server. An OK is followed by
+/// ok_length bytes, which depends on what was requested.
+pub fn read_response<R: Read>(server: &mut R, ok_length: usize) -> Result<Response, Errors> {
+  let message = match read::read_exact(server, 1) {
+    Ok(m) => [m[0]],
+    Err(Errors::ReadRetryError) => return Err(Errors::TimeoutError("No response from the server".to_string())),
+    Err(e) => return Err(e)
+  };
+
+  let response = match message {
+    OK_MESSAGE => Response::Ok(read::read_exact(server, ok_length)?),
+    ERR_MESSAGE => Response::Err,
+    CONTINUE_MESSAGE => Response::Continue,
+    RETRY_MESSAGE => Response::Retry(RetryHint::from_bytes(&read::read_exact(server, RETRY_HINT_BYTES)?)?),
+    NO_LEASE_MESSAGE => Response::NoLease,
+    LEASE_IN_USE_MESSAGE => Response::LeaseInUse,
+    CHECKSUM_MISMATCH_MESSAGE => Response::ChecksumMismatch,
+    DUPLICATE_CHUNK_MESSAGE => Response::DuplicateChunk,
+    OUT_OF_RANGE_MESSAGE => Response::OutOfRange,
+    FILE_EXISTS_MESSAGE => Response::FileExists,
+    _ => return Err(Errors::ParseError(format!("Unknown response message {}", message[0])))
+  };
+
+  return Ok(response);
+}
+
+/// What a lease has received so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseStatus {
+  pub bytes_left: u32,
+  pub chunks_sent: u32,
+  /// received chunk_nums as sorted, inclusive ranges
+  pub ranges: Vec<(u32, u32)>
+}
+
+impl LeaseStatus {
+  pub fn has_chunk(&self, chunk_num: u32) -> bool {
+    return self.ranges.iter().any(|(start, end)| *start <= chunk_num && chunk_num <= *end);
+  }
+}
+
+/// Reads the ranges that follow the first STATUS_BYTES of a
+/// STATUS response. There can't be more ranges than chunks sent,
+/// or than max_ranges, the chunks in the file if it's known.
+/// Ranges are read one at a time, so a bad count can't make
+/// the client allocate more than the server actually sends.
+pub fn read_status<R: Read>(head: &[u8], server: &mut R, max_ranges: u32) -> Result<LeaseStatus, Errors> {
+  if head.len() != STATUS_BYTES {
+    return Err(Errors::ParseError("Status is missing its counts".to_string()));
+  }
+
+  let bytes_left = util::read_u32(&head[0..4].to_vec())?;
+  let chunks_sent = util::read_u32(&head[4..8].to_vec())?;
+  let range_count = util::read_u32(&head[8..12].to_vec())?;
+
+  if range_count > chunks_sent.min(max_ranges) {
+    return Err(Errors::ParseError(format!("Status has {} ranges for {} chunks", range_count, chunks_sent.min(max_ranges))));
+  }
+
+  let mut ranges = Vec::new();
+  for _ in 0..range_count {
+    let r = read::read_exact(server, 8)?;
+    let start = u32::from_le_bytes([r[0], r[1], r[2], r[3]]);
+    let end = u32::from_le_bytes([r[4], r[5], r[6], r[7]]);
+    ranges.push((start, end));
+  }
+
+  return Ok(LeaseStatus { bytes_left, chunks_sent, ranges });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn reads_responses_and_their_payloads() {
+    let mut ok = vec![1u8];
+    ok.extend(&[7u8; 16]);
+    assert_eq!(read_response(&mut Cursor::new(ok), 16).unwrap(), Response::Ok(vec![7u8; 16]));
+
+    let hint = RetryHint { retry_after_ms: 500, queued_chunks: 3, leases: 9 };
+    let mut retry = vec![4u8];
+    retry.extend(hint.to_bytes());
+    assert_eq!(read_response(&mut Cursor::new(retry), 0).unwrap(), Response::Retry(hint));
+
+    assert_eq!(read_response(&mut Cursor::new(vec![5u8]), 0).unwrap(), Response::NoLease);
+    assert!(read_response(&mut Cursor::new(vec![42u8]), 0).is_err());
+  }
+
+  #[test]
+  fn reads_the_status_ranges() {
+    let mut head = Vec::new();
+    head.extend(&100u32.to_le_bytes());
+    head.extend(&4u32.to_le_bytes());
+    head.extend(&2u32.to_le_bytes());
+    let mut ranges = Vec::new();
+    for n in [0u32, 2, 5, 5].iter() {
+      ranges.extend(&n.to_le_bytes());
+    }
+
+    let status = read_status(&head, &mut Cursor::new(ranges.clone()), u32::MAX).unwrap();
+    assert_eq!(status, LeaseStatus { bytes_left: 100, chunks_sent: 4, ranges: vec![(0, 2), (5, 5)] });
+    assert!(status.has_chunk(1));
+    assert!(!status.has_chunk(3));
+    assert!(status.has_chunk(5));
+
+    // more ranges than the file has chunks
+    assert!(read_status(&head, &mut Cursor::new(ranges), 1).is_err());
+
+    // more ranges than chunks sent, and than were sent
+    let mut head = head[..8].to_vec();
+    head.extend(&u32::MAX.to_le_bytes());
+    assert!(read_status(&head, &mut Cursor::new(Vec::new()), u32::MAX).is_err());
+  }
+}
@@ -0,0 +1,541 @@
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf, Component};
+use std::fs;
+use std::env;
+
+use crate::errors::Errors;
+use crate::headers::{MIN_CHUNK_BYTES, MAX_CHUNK_BYTES};
+
+pub const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:7878";
+pub const DEFAULT_STORAGE_DIR: &str = ".";
+pub const DEFAULT_INCOMING_DIR: &str = "incoming";
+pub const DEFAULT_COMPLETE_DIR: &str = "complete";
+pub const DEFAULT_QUARANTINE_DIR: &str = "quarantine";
+pub const DEFAULT_LEASE_TTL_SECS: u64 = 300; // 5 minutes
+pub const DEFAULT_REAP_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_READ_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_MIN_THROUGHPUT: u32 = 16000; // 16 KB/s
+pub const DEFAULT_CHUNK_RETRIES: u32 = 3;
+pub const DEFAULT_QUEUE_DEPTH: usize = 1024;
+pub const DEFAULT_MAX_LEASES: usize = 1024;
+pub const DEFAULT_MAX_QUEUED_BYTES: u64 = 64000000; // 64 MB
+pub const DEFAULT_RETRY_AFTER_MS: u64 = 1000;
+pub const DEFAULT_MAX_IN_FLIGHT_BYTES: u64 = 16000000; // 16 MB
+pub const DEFAULT_MAX_LEASE_WRITERS: u32 = 4;
+
+/// Environment variables are the config keys in
+/// upper case with this prefix, e.g. RJCHUNKER_LEASE_TTL_SECS.
+pub const ENV_PREFIX: &str = "RJCHUNKER_";
+
+/// Keys a config file or the environment can set.
+pub const CONFIG_KEYS: [&str; 23] = [
+  "bind_address",
+  "storage_dir",
+  "incoming_dir",
+  "complete_dir",
+  "quarantine_dir",
+  "lease_ttl_secs",
+  "reap_interval_secs",
+  "lease_journal",
+  "read_timeout_secs",
+  "write_timeout_secs",
+  "min_throughput",
+  "min_chunk_bytes",
+  "max_chunk_bytes",
+  "chunk_retries",
+  "process_workers",
+  "assembler_workers",
+  "backend",
+  "queue_depth",
+  "max_leases",
+  "max_queued_bytes",
+  "retry_after_ms",
+  "max_in_flight_bytes",
+  "max_lease_writers",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+  /// blocking accept loop, the process workers
+  /// read requests straight off of the clients
+  Threaded,
+  /// readiness based event loop that buffers whole
+  /// requests before handing them to the process workers
+  #[cfg(all(feature = "mio", unix))]
+  EventLoop,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+  /// address the server listens on
+  pub bind_address: String,
+  /// where spools and uploaded files are kept
+  pub storage_dir: PathBuf,
+  /// spools of uploads in progress. Relative
+  /// paths are within the storage_dir.
+  pub incoming_dir: PathBuf,
+  /// finished uploads are moved into here whole
+  pub complete_dir: PathBuf,
+  /// uploads that didn't match their hash
+  pub quarantine_dir: PathBuf,
+  /// leases that haven't received a chunk
+  /// for this long are expired
+  pub lease_ttl: Duration,
+  /// how often to look for expired leases
+  pub reap_interval: Duration,
+  /// file leases are journaled to so they survive restarts.
+  /// Relative to the storage_dir. Kept in memory if None.
+  pub lease_journal: Option<PathBuf>,
+  /// how long a read on a connection may wait for data
+  pub read_timeout: Duration,
+  /// how long a write to a connection may block
+  pub write_timeout: Duration,
+  /// slowest a chunk body may arrive in bytes per second,
+  /// on top of the read_timeout. 0 turns it off.
+  pub min_throughput: u32,
+  /// smallest chunk, other than the last one of a file
+  pub min_chunk_bytes: u32,
+  /// largest chunk. No more than MAX_CHUNK_BYTES.
+  pub max_chunk_bytes: u32,
+  /// times a chunk body read is retried
+  /// when no data has arrived yet
+  pub chunk_retries: u32,
+  /// threads reading requests off of connections
+  pub process_workers: usize,
+  /// threads writing chunks to spools
+  pub assembler_workers: usize,
+  /// how connections are accepted and read from
+  pub backend: Backend,
+  /// requests that can wait between stages before
+  /// new clients are turned away. At least 1.
+  pub queue_depth: usize,
+  /// most leases open at once. 0 is unlimited.
+  pub max_leases: usize,
+  /// most chunk bytes waiting to be written to
+  /// spools at once. 0 is unlimited.
+  pub max_queued_bytes: u64,
+  /// how long a turned away client is told to wait
+  pub retry_after: Duration,
+  /// most chunk bytes read into memory at once. Workers wait
+  /// for the assembler to catch up before reading more chunk
+  /// bodies. 0 is unlimited.
+  pub max_in_flight_bytes: u64,
+  /// chunks of one lease that can be read and written
+  /// at once, each over its own connection. At least 1.
+  pub max_lease_writers: u32,
+}
+
+impl Default for ServerConfig {
+  fn default() -> ServerConfig {
+    return ServerConfig {
+      bind_address: DEFAULT_BIND_ADDRESS.to_string(),
+      storage_dir: PathBuf::from(DEFAULT_STORAGE_DIR),
+      incoming_dir: PathBuf::from(DEFAULT_INCOMING_DIR),
+      complete_dir: PathBuf::from(DEFAULT_COMPLETE_DIR),
+      quarantine_dir: PathBuf::from(DEFAULT_QUARANTINE_DIR),
+      lease_ttl: Duration::from_secs(DEFAULT_LEASE_TTL_SECS),
+      reap_interval: Duration::from_secs(DEFAULT_REAP_INTERVAL_SECS),
+      lease_journal: None,
+      read_timeout: Duration::from_secs(DEFAULT_READ_TIMEOUT_SECS),
+      write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT_SECS),
+      min_throughput: DEFAULT_MIN_THROUGHPUT,
+      min_chunk_bytes: MIN_CHUNK_BYTES,
+      max_chunk_bytes: MAX_CHUNK_BYTES,
+      chunk_retries: DEFAULT_CHUNK_RETRIES,
+      // mostly waiting on clients, so more than the cores
+      process_workers: num_cpus::get() * 4,
+      assembler_workers: num_cpus::get(),
+      backend: Backend::Threaded,
+      queue_depth: DEFAULT_QUEUE_DEPTH,
+      max_leases: DEFAULT_MAX_LEASES,
+      max_queued_bytes: DEFAULT_MAX_QUEUED_BYTES,
+      retry_after: Duration::from_millis(DEFAULT_RETRY_AFTER_MS),
+      max_in_flight_bytes: DEFAULT_MAX_IN_FLIGHT_BYTES,
+      max_lease_writers: DEFAULT_MAX_LEASE_WRITERS,
+    };
+  }
+}
+
+impl ServerConfig {
+  pub fn builder() -> ServerConfigBuilder {
+    return ServerConfigBuilder::default();
+  }
+
+  /// When a chunk body of the length has to be read by, if
+  /// it's started reading now.
+  pub fn chunk_deadline(&self, chunk_length: u32) -> Option<Instant> {
+    if self.min_throughput == 0 {
+      return None;
+    }
+
+    let transfer = Duration::from_secs_f64(chunk_length as f64 / self.min_throughput as f64);
+    return Some(Instant::now() + self.read_timeout + transfer);
+  }
+
+  pub fn incoming_path(&self) -> PathBuf {
+    return self.storage_dir.join(&self.incoming_dir);
+  }
+
+  pub fn complete_path(&self) -> PathBuf {
+    return self.storage_dir.join(&self.complete_dir);
+  }
+
+  pub fn quarantine_path(&self) -> PathBuf {
+    return self.storage_dir.join(&self.quarantine_dir);
+  }
+
+  /// Where leases are journaled, within the storage_dir if the
+  /// lease_journal is relative.
+  pub fn lease_journal_path(&self) -> Option<PathBuf> {
+    return self.lease_journal.as_ref().map(|j| self.storage_dir.join(j));
+  }
+
+  /// Checks the settings work together. Done by `build`, and
+  /// again when a server starts, since the fields can be set
+  /// without the builder.
+  pub fn check(&self) -> Result<(), Errors> {
+    if self.min_chunk_bytes == 0 || self.min_chunk_bytes > self.max_chunk_bytes {
+      return Err(Errors::ConfigError("min_chunk_bytes must be between 1 and max_chunk_bytes".to_string()));
+    }
+
+    if self.max_chunk_bytes > MAX_CHUNK_BYTES {
+      return Err(Errors::ConfigError(format!("max_chunk_bytes can't be more than {}", MAX_CHUNK_BYTES)));
+    }
+
+    if self.process_workers == 0 || self.assembler_workers == 0 {
+      return Err(Errors::ConfigError("There must be at least one worker per stage".to_string()));
+    }
+
+    if self.queue_depth == 0 {
+      return Err(Errors::ConfigError("queue_depth must be at least 1".to_string()));
+    }
+
+    if self.max_lease_writers == 0 {
+      return Err(Errors::ConfigError("max_lease_writers must be at least 1".to_string()));
+    }
+
+    // spools are swept and files moved between these,
+    // so each needs a directory of its own
+    let incoming = normalize(&self.incoming_path());
+    let complete = normalize(&self.complete_path());
+    let quarantine = normalize(&self.quarantine_path());
+    if incoming == complete || incoming == quarantine || complete == quarantine {
+      return Err(Errors::ConfigError("incoming_dir, complete_dir and quarantine_dir must all be different".to_string()));
+    }
+
+    if let Some(lease_journal) = self.lease_journal_path() {
+      if normalize(&lease_journal).starts_with(&incoming) {
+        return Err(Errors::ConfigError("lease_journal can't be in the incoming_dir".to_string()));
+      }
+    }
+
+    return Ok(());
+  }
+
+  /// Creates the storage directories that don't exist yet.
+  pub fn create_dirs(&self) -> Result<(), Errors> {
+    for dir in [self.incoming_path(), self.complete_path(), self.quarantine_path()].iter() {
+      if fs::create_dir_all(dir).is_err() {
+        return Err(Errors::FileIOError(format!("Failed to create {:?}", dir)));
+      }
+    }
+
+    return Ok(());
+  }
+}
+
+/// Builds a checked `ServerConfig`. Starts from the defaults,
+/// then a config file and the environment can be layered on,
+/// with whatever is set last winning.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfigBuilder {
+  config: ServerConfig
+}
+
+impl ServerConfigBuilder {
+  pub fn bind_address<S: Into<String>>(mut self, bind_address: S) -> ServerConfigBuilder {
+    self.config.bind_address = bind_address.into();
+    return self;
+  }
+
+  pub fn storage_dir<P: Into<PathBuf>>(mut self, storage_dir: P) -> ServerConfigBuilder {
+    self.config.storage_dir = storage_dir.into();
+    return self;
+  }
+
+  pub fn incoming_dir<P: Into<PathBuf>>(mut self, incoming_dir: P) -> ServerConfigBuilder {
+    self.config.incoming_dir = incoming_dir.into();
+    return self;
+  }
+
+  pub fn complete_dir<P: Into<PathBuf>>(mut self, complete_dir: P) -> ServerConfigBuilder {
+    self.config.complete_dir = complete_dir.into();
+    return self;
+  }
+
+  pub fn quarantine_dir<P: Into<PathBuf>>(mut self, quarantine_dir: P) -> ServerConfigBuilder {
+    self.config.quarantine_dir = quarantine_dir.into();
+    return self;
+  }
+
+  pub fn lease_ttl(mut self, lease_ttl: Duration) -> ServerConfigBuilder {
+    self.config.lease_ttl = lease_ttl;
+    return self;
+  }
+
+  pub fn reap_interval(mut self, reap_interval: Duration) -> ServerConfigBuilder {
+    self.config.reap_interval = reap_interval;
+    return self;
+  }
+
+  pub fn lease_journal<P: Into<PathBuf>>(mut self, lease_journal: P) -> ServerConfigBuilder {
+    self.config.lease_journal = Some(lease_journal.into());
+    return self;
+  }
+
+  pub fn read_timeout(mut self, read_timeout: Duration) -> ServerConfigBuilder {
+    self.config.read_timeout = read_timeout;
+    return self;
+  }
+
+  pub fn write_timeout(mut self, write_timeout: Duration) -> ServerConfigBuilder {
+    self.config.write_timeout = write_timeout;
+    return self;
+  }
+
+  pub fn min_throughput(mut self, min_throughput: u32) -> ServerConfigBuilder {
+    self.config.min_throughput = min_throughput;
+    return self;
+  }
+
+  pub fn chunk_bytes(mut self, min_chunk_bytes: u32, max_chunk_bytes: u32) -> ServerConfigBuilder {
+    self.config.min_chunk_bytes = min_chunk_bytes;
+    self.config.max_chunk_bytes = max_chunk_bytes;
+    return self;
+  }
+
+  pub fn chunk_retries(mut self, chunk_retries: u32) -> ServerConfigBuilder {
+    self.config.chunk_retries = chunk_retries;
+    return self;
+  }
+
+  pub fn process_workers(mut self, process_workers: usize) -> ServerConfigBuilder {
+    self.config.process_workers = process_workers;
+    return self;
+  }
+
+  pub fn assembler_workers(mut self, assembler_workers: usize) -> ServerConfigBuilder {
+    self.config.assembler_workers = assembler_workers;
+    return self;
+  }
+
+  pub fn backend(mut self, backend: Backend) -> ServerConfigBuilder {
+    self.config.backend = backend;
+    return self;
+  }
+
+  pub fn queue_depth(mut self, queue_depth: usize) -> ServerConfigBuilder {
+    self.config.queue_depth = queue_depth;
+    return self;
+  }
+
+  pub fn max_leases(mut self, max_leases: usize) -> ServerConfigBuilder {
+    self.config.max_leases = max_leases;
+    return self;
+  }
+
+  pub fn max_queued_bytes(mut self, max_queued_bytes: u64) -> ServerConfigBuilder {
+    self.config.max_queued_bytes = max_queued_bytes;
+    return self;
+  }
+
+  pub fn retry_after(mut self, retry_after: Duration) -> ServerConfigBuilder {
+    self.config.retry_after = retry_after;
+    return self;
+  }
+
+  pub fn max_in_flight_bytes(mut self, max_in_flight_bytes: u64) -> ServerConfigBuilder {
+    self.config.max_in_flight_bytes = max_in_flight_bytes;
+    return self;
+  }
+
+  pub fn max_lease_writers(mut self, max_lease_writers: u32) -> ServerConfigBuilder {
+    self.config.max_lease_writers = max_lease_writers;
+    return self;
+  }
+
+  /// Sets the keys in the TOML file. Keys are
+  /// those in CONFIG_KEYS, all at the top level.
+  pub fn toml_file<P: AsRef<Path>>(self, location: P) -> Result<ServerConfigBuilder, Errors> {
+    let contents = match fs::read_to_string(location.as_ref()) {
+      Ok(c) => c,
+      Err(_) => return Err(Errors::FileIOError(format!("Failed to read config {:?}", location.as_ref())))
+    };
+
+    return self.toml_str(&contents);
+  }
+
+  pub fn toml_str(mut self, contents: &str) -> Result<ServerConfigBuilder, Errors> {
+    let table = match contents.parse::<toml::Table>() {
+      Ok(t) => t,
+      Err(e) => return Err(Errors::ConfigError(format!("Invalid config: {}", e)))
+    };
+
+    for (key, value) in table.iter() {
+      let value = match value {
+        toml::Value::String(s) => s.to_string(),
+        toml::Value::Integer(i) => i.to_string(),
+        _ => return Err(Errors::ConfigError(format!("{} must be a string or integer", key)))
+      };
+      self.set(key, &value)?;
+    }
+
+    return Ok(self);
+  }
+
+  /// Sets the keys found in RJCHUNKER_ prefixed
+  /// environment variables.
+  pub fn env(mut self) -> Result<ServerConfigBuilder, Errors> {
+    for key in CONFIG_KEYS.iter() {
+      if let Ok(value) = env::var(format!("{}{}", ENV_PREFIX, key.to_uppercase())) {
+        self.set(key, &value)?;
+      }
+    }
+
+    return Ok(self);
+  }
+
+  /// Sets a config key from its text value.
+  pub fn set(&mut self, key: &str, value: &str) -> Result<(), Errors> {
+    let config = &mut self.config;
+
+    match key {
+      "bind_address" => config.bind_address = value.to_string(),
+      "storage_dir" => config.storage_dir = PathBuf::from(value),
+      "incoming_dir" => config.incoming_dir = PathBuf::from(value),
+      "complete_dir" => config.complete_dir = PathBuf::from(value),
+      "quarantine_dir" => config.quarantine_dir = PathBuf::from(value),
+      "lease_ttl_secs" => config.lease_ttl = Duration::from_secs(parse(key, value)?),
+      "reap_interval_secs" => config.reap_interval = Duration::from_secs(parse(key, value)?),
+      "lease_journal" => config.lease_journal = Some(PathBuf::from(value)),
+      "read_timeout_secs" => config.read_timeout = Duration::from_secs(parse(key, value)?),
+      "write_timeout_secs" => config.write_timeout = Duration::from_secs(parse(key, value)?),
+      "min_throughput" => config.min_throughput = parse(key, value)?,
+      "min_chunk_bytes" => config.min_chunk_bytes = parse(key, value)?,
+      "max_chunk_bytes" => config.max_chunk_bytes = parse(key, value)?,
+      "chunk_retries" => config.chunk_retries = parse(key, value)?,
+      "process_workers" => config.process_workers = parse(key, value)?,
+      "assembler_workers" => config.assembler_workers = parse(key, value)?,
+      "backend" => config.backend = parse_backend(value)?,
+      "queue_depth" => config.queue_depth = parse(key, value)?,
+      "max_leases" => config.max_leases = parse(key, value)?,
+      "max_queued_bytes" => config.max_queued_bytes = parse(key, value)?,
+      "retry_after_ms" => config.retry_after = Duration::from_millis(parse(key, value)?),
+      "max_in_flight_bytes" => config.max_in_flight_bytes = parse(key, value)?,
+      "max_lease_writers" => config.max_lease_writers = parse(key, value)?,
+      _ => return Err(Errors::ConfigError(format!("Unknown config key {}", key)))
+    };
+
+    return Ok(());
+  }
+
+  /// Checks the settings work together.
+  pub fn build(self) -> Result<ServerConfig, Errors> {
+    self.config.check()?;
+
+    return Ok(self.config);
+  }
+}
+
+/// The path with `.` and `..` worked out without touching the
+/// file system, since the directories may not exist yet.
+fn normalize(path: &Path) -> PathBuf {
+  let mut normal = PathBuf::new();
+
+  for component in path.components() {
+    match component {
+      Component::CurDir => (),
+      Component::ParentDir if normal.file_name().is_some() => {
+        normal.pop();
+      },
+      c => normal.push(c.as_os_str())
+    };
+  }
+
+  return normal;
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Errors> {
+  return match value.trim().parse::<T>() {
+    Ok(v) => Ok(v),
+    Err(_) => Err(Errors::ConfigError(format!("Invalid value {:?} for {}", value, key)))
+  };
+}
+
+fn parse_backend(value: &str) -> Result<Backend, Errors> {
+  return match value {
+    "threaded" => Ok(Backend::Threaded),
+    #[cfg(all(feature = "mio", unix))]
+    "event_loop" => Ok(Backend::EventLoop),
+    _ => Err(Errors::ConfigError(format!("Unknown backend {}", value)))
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn layers_settings_over_the_defaults() {
+    let config = ServerConfig::builder()
+      .bind_address("127.0.0.1:9000")
+      .toml_str("storage_dir = \"/tmp/uploads\"\nlease_ttl_secs = 60\nmax_chunk_bytes = 4000")
+      .unwrap()
+      .chunk_retries(5)
+      .build()
+      .unwrap();
+
+    assert_eq!(config.bind_address, "127.0.0.1:9000");
+    assert_eq!(config.storage_dir, PathBuf::from("/tmp/uploads"));
+    assert_eq!(config.lease_ttl, Duration::from_secs(60));
+    assert_eq!(config.max_chunk_bytes, 4000);
+    assert_eq!(config.min_chunk_bytes, MIN_CHUNK_BYTES);
+    assert_eq!(config.chunk_retries, 5);
+  }
+
+  #[test]
+  fn rejects_bad_settings() {
+    assert!(ServerConfig::builder().toml_str("lease_ttl_secs = \"soon\"").is_err());
+    assert!(ServerConfig::builder().toml_str("chunk_size = 10").is_err());
+    assert!(ServerConfig::builder().chunk_bytes(2000, 1000).build().is_err());
+    assert!(ServerConfig::builder().chunk_bytes(1000, MAX_CHUNK_BYTES + 1).build().is_err());
+    assert!(ServerConfig::builder().process_workers(0).build().is_err());
+  }
+
+  #[test]
+  fn keeps_the_storage_paths_apart() {
+    assert!(ServerConfig::builder().complete_dir("incoming").build().is_err());
+    assert!(ServerConfig::builder().quarantine_dir("./complete/../complete").build().is_err());
+    assert!(ServerConfig::builder().storage_dir("/tmp/uploads").incoming_dir("/tmp/uploads/complete").build().is_err());
+    assert!(ServerConfig::builder().lease_journal("incoming/leases.journal").build().is_err());
+    assert!(ServerConfig::builder().lease_journal("complete/../incoming/leases.journal").build().is_err());
+
+    let config = ServerConfig::builder()
+      .storage_dir("/tmp/uploads")
+      .lease_journal("leases.journal")
+      .build()
+      .unwrap();
+    assert_eq!(config.lease_journal_path(), Some(PathBuf::from("/tmp/uploads/leases.journal")));
+
+    let config = ServerConfig::builder()
+      .storage_dir("/tmp/uploads")
+      .lease_journal("/var/lib/rjchunker/leases.journal")
+      .build()
+      .unwrap();
+    assert_eq!(config.lease_journal_path(), Some(PathBuf::from("/var/lib/rjchunker/leases.journal")));
+
+    let config = ServerConfig { complete_dir: PathBuf::from("incoming"), ..ServerConfig::default() };
+    assert!(config.check().is_err());
+  }
+}
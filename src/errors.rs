@@ -1,11 +1,27 @@
+use crate::admission::RetryHint;
+
 #[derive(Debug)]
 pub enum Errors {
   ReadError(String),
   ReadLengthError(String),
   ReadRetryError,
+  ReadClosedError,
+  TimeoutError(String),
   WriteError(String),
   ParseError(String),
   InvalidRequest(String),
   FileIOError(String),
+  ChecksumError(String),
+  ChunkChecksumError(String),
+  OutOfRangeError(String),
+  NoLeaseError(String),
+  LeaseInUseError(String),
+  /// complete already has a file of the upload's name
+  FileExistsError(String),
+  /// the server is shedding load
+  BusyError(RetryHint),
+  ConfigError(String),
+  /// the server turned a client request down
+  ResponseError(String),
   UnexpectedError(String),
 }
\ No newline at end of file
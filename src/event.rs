@@ -0,0 +1,395 @@
+use std::io::{Read, Cursor, ErrorKind};
+use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream, Shutdown};
+use std::os::unix::io::{IntoRawFd, FromRawFd};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crossbeam_channel::Sender;
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{TcpListener, TcpStream};
+use log::{warn, error};
+
+use crate::{Request, Cache, admission};
+use crate::config::ServerConfig;
+use crate::handle::ShutdownSignal;
+use crate::errors::Errors;
+use crate::headers::Headers;
+use crate::headers::read::{read_headers, header_length, frame_length};
+use crate::io;
+
+const SERVER: Token = Token(0);
+const POLL_INTERVAL_MS: u64 = 250;
+const READ_BUFFER_BYTES: usize = 64 * 1024;
+
+/// A connection that hasn't sent its whole request yet.
+struct Connection {
+  stream: TcpStream,
+  buffer: Vec<u8>,
+  /// parsed and admitted once they're buffered
+  headers: Option<Headers>,
+  frame_length: Option<usize>,
+  /// chunk bytes taken from the in flight budget on admission
+  in_flight: u64,
+  deadline: Option<Instant>,
+  last_read: Instant
+}
+
+/// Readiness based alternative to `server::start`. Idle and
+/// slow connections only cost a buffer, not a thread. Requests
+/// are read in pieces as data arrives and admitted once their
+/// headers are in, then handed to the process stage once the
+/// chunk body is buffered too.
+pub fn start(std_listener: StdTcpListener, config: Arc<ServerConfig>, cache: Arc<Cache>, process_s: Sender<Request>, shutdown: ShutdownSignal) {
+  std_listener.set_nonblocking(true).expect("Failed to set listener non blocking");
+  let mut listener = TcpListener::from_std(std_listener);
+
+  let mut poll = Poll::new().expect("Failed to create poll");
+  let mut events = Events::with_capacity(1024);
+  poll.registry()
+    .register(&mut listener, SERVER, Interest::READABLE)
+    .expect("Failed to register listener");
+
+  let mut connections: HashMap<Token, Connection> = HashMap::new();
+  let mut next_token = 1;
+  let mut buffer = vec![0u8; READ_BUFFER_BYTES];
+
+  let mut stopped = false;
+  while !stopped && !shutdown.is_set() {
+    if let Err(err) = poll.poll(&mut events, Some(Duration::from_millis(POLL_INTERVAL_MS))) {
+      if err.kind() != ErrorKind::Interrupted {
+        error!("{}", err);
+      }
+      continue;
+    }
+
+    for event in events.iter() {
+      if event.token() == SERVER {
+        accept(&poll, &mut listener, &mut connections, &mut next_token);
+        continue;
+      }
+
+      let token = event.token();
+      let result = match connections.get_mut(&token) {
+        Some(connection) => receive(connection, &config, &cache, &mut buffer),
+        None => continue
+      };
+
+      match result {
+        Ok(false) => (),
+        Ok(true) => {
+          let mut connection = connections.remove(&token).unwrap();
+          poll.registry().deregister(&mut connection.stream).ok();
+          let request = match into_request(connection, &config, &cache) {
+            Ok(r) => r,
+            Err(e) => {
+              error!("{:?}", e);
+              continue;
+            }
+          };
+          // the process stage only stops once it can't serve
+          // any more, so neither can the loop
+          if let Err(e) = admission::forward(request, &process_s, &cache) {
+            error!("{:?}", e);
+            stopped = true;
+            break;
+          }
+        },
+        Err(e) => {
+          let mut connection = connections.remove(&token).unwrap();
+          poll.registry().deregister(&mut connection.stream).ok();
+          reject(connection, &cache, &e);
+        }
+      };
+    }
+
+    expire(&poll, &mut connections, &config, &cache);
+  }
+
+  // requests still being read won't be, so the
+  // clients are told to come back later
+  for (_, mut connection) in connections.drain() {
+    poll.registry().deregister(&mut connection.stream).ok();
+    reject(connection, &cache, &Errors::BusyError(admission::RetryHint::new(&cache)));
+  }
+}
+
+fn accept(poll: &Poll, listener: &mut TcpListener, connections: &mut HashMap<Token, Connection>, next_token: &mut usize) {
+  loop {
+    match listener.accept() {
+      Ok((mut stream, _)) => {
+        let token = Token(*next_token);
+        *next_token += 1;
+
+        if let Err(err) = poll.registry().register(&mut stream, token, Interest::READABLE) {
+          error!("{}", err);
+          continue;
+        }
+
+        connections.insert(token, Connection {
+          stream,
+          buffer: Vec::new(),
+          headers: None,
+          frame_length: None,
+          in_flight: 0,
+          deadline: None,
+          last_read: Instant::now()
+        });
+      },
+      Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+      Err(err) => {
+        error!("{}", err);
+        return;
+      }
+    };
+  }
+}
+
+/// Reads what the connection has ready, no further than the end
+/// of the headers until they're admitted, then no further than
+/// the end of the frame. True once the entire request is buffered.
+fn receive(connection: &mut Connection, config: &Arc<ServerConfig>, cache: &Arc<Cache>, buffer: &mut [u8]) -> Result<bool, Errors> {
+  loop {
+    let wanted = match (connection.frame_length, connection.buffer.first()) {
+      (Some(frame_length), _) => frame_length - connection.buffer.len(),
+      (None, Some(params)) => header_length(*params) - connection.buffer.len(),
+      (None, None) => 1
+    };
+
+    if wanted == 0 && connection.headers.is_none() {
+      admit(connection, config, cache)?;
+      continue;
+    }
+
+    // a client may close its end once the whole request is
+    // sent, but nothing else may follow the frame
+    if wanted == 0 {
+      return match connection.stream.read(&mut buffer[..1]) {
+        Ok(0) => Ok(true),
+        Ok(_) => Err(Errors::InvalidRequest("Request continues past its frame".to_string())),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+        Err(e) => Err(Errors::ReadError(format!("Failed to read bytes: {}", e)))
+      };
+    }
+
+    let length = wanted.min(buffer.len());
+    match connection.stream.read(&mut buffer[..length]) {
+      Ok(0) => return Err(Errors::ReadClosedError),
+      Ok(length) => {
+        connection.buffer.extend_from_slice(&buffer[..length]);
+        connection.last_read = Instant::now();
+      },
+      Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+      Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+      Err(e) => return Err(Errors::ReadError(format!("Failed to read bytes: {}", e)))
+    };
+  }
+}
+
+/// Parses the buffered headers and admits the request, so a
+/// client the server has no room for is sent RETRY before its
+/// chunk body is read.
+fn admit(connection: &mut Connection, config: &Arc<ServerConfig>, cache: &Arc<Cache>) -> Result<(), Errors> {
+  let frame_length = match frame_length(&connection.buffer)? {
+    Some(f) => f,
+    None => return Err(Errors::ReadLengthError("Headers aren't buffered yet".to_string()))
+  };
+  let header_length = header_length(connection.buffer[0]);
+
+  let headers = read_headers(&mut Cursor::new(&connection.buffer[..header_length]))?;
+  connection.in_flight = admission::admit_headers(cache, &headers)?;
+  connection.headers = Some(headers);
+  connection.frame_length = Some(frame_length);
+  connection.deadline = config.chunk_deadline((frame_length - header_length) as u32);
+
+  return Ok(());
+}
+
+/// Drops connections that went quiet or are sending
+/// their chunk slower than the minimum throughput.
+fn expire(poll: &Poll, connections: &mut HashMap<Token, Connection>, config: &Arc<ServerConfig>, cache: &Arc<Cache>) {
+  let now = Instant::now();
+  let expired: Vec<Token> = connections.iter()
+    .filter(|(_, c)| {
+      now.duration_since(c.last_read) > config.read_timeout
+        || c.deadline.map(|d| now > d).unwrap_or(false)
+    })
+    .map(|(t, _)| *t)
+    .collect();
+
+  for token in expired {
+    let mut connection = connections.remove(&token).unwrap();
+    poll.registry().deregister(&mut connection.stream).ok();
+    reject(connection, cache, &Errors::TimeoutError("Connection too slow".to_string()));
+  }
+}
+
+/// mio has no safe way back to a std stream, which is why
+/// the event loop is only built for unix targets.
+fn into_std(stream: TcpStream) -> StdTcpStream {
+  // the fd is taken out of the mio stream, so it's only owned once
+  return unsafe { StdTcpStream::from_raw_fd(stream.into_raw_fd()) };
+}
+
+/// Answers the request with the error, unless the client is gone,
+/// and gives back the chunk bytes it was admitted with.
+fn reject(connection: Connection, cache: &Arc<Cache>, error: &Errors) {
+  cache.load.free(connection.in_flight);
+
+  if let Errors::ReadClosedError = error {
+    return;
+  }
+
+  warn!("{:?}", error);
+
+  let mut client = into_std(connection.stream);
+  if client.set_nonblocking(false).is_ok() {
+    io::write::write_error(&mut client, error).ok();
+  }
+  client.shutdown(Shutdown::Both).ok();
+}
+
+/// The buffered request, along with the now blocking client,
+/// for the process stage to respond to.
+fn into_request(connection: Connection, config: &Arc<ServerConfig>, cache: &Arc<Cache>) -> Result<Request, Errors> {
+  let frame_length = connection.frame_length.unwrap();
+  let header_length = header_length(connection.buffer[0]);
+  let headers = connection.headers.unwrap();
+
+  let client = into_std(connection.stream);
+  let blocking = client.set_nonblocking(false)
+    .and_then(|_| client.set_read_timeout(Some(config.read_timeout)))
+    .and_then(|_| client.set_write_timeout(Some(config.write_timeout)));
+  if blocking.is_err() {
+    cache.load.free(connection.in_flight);
+    client.shutdown(Shutdown::Both).ok();
+    return Err(Errors::UnexpectedError("Failed to make client blocking".to_string()));
+  }
+
+  let chunk = match headers.chunk_length {
+    Some(_) => Some(connection.buffer[header_length..frame_length].to_vec()),
+    None => None
+  };
+
+  return Ok(Request {
+    client,
+    lease: None,
+    headers: Some(headers),
+    chunk,
+    response: None,
+    admitted: true
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Read, Write};
+  use std::net::TcpStream;
+  use crate::spawn_server;
+  use crate::config::{ServerConfig, Backend};
+  use crate::headers::{Headers, HeaderType, MIN_CHUNK_BYTES};
+  use crate::headers::write::write_headers;
+  use crate::io::write::{ERR_MESSAGE, NO_LEASE_MESSAGE, RETRY_MESSAGE};
+  use super::*;
+
+  fn event_config(name: &str) -> ServerConfig {
+    return ServerConfig::builder()
+      .bind_address("127.0.0.1:0")
+      .storage_dir(std::env::temp_dir().join(name))
+      .backend(Backend::EventLoop)
+      .build()
+      .unwrap();
+  }
+
+  fn chunk_headers(chunk_num: u32) -> Vec<u8> {
+    let headers = Headers {
+      header_type: HeaderType::ERROR,
+      lease_id: Some("5c0e2a4b-6d8f-4a1c-9e3b-7f5d1c3a9e02".to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: Some(MIN_CHUNK_BYTES),
+      chunk_num: Some(chunk_num),
+      chunk_checksum: Some(0),
+      cancel: None
+    };
+    let mut frame = Vec::new();
+    write_headers(&headers, &mut frame).unwrap();
+    return frame;
+  }
+
+  #[test]
+  fn answers_requests_sent_before_a_half_close() {
+    let handle = spawn_server(event_config("rjchunker_event_close_test")).unwrap();
+
+    let headers = Headers {
+      header_type: HeaderType::ERROR,
+      lease_id: Some("5c0e2a4b-6d8f-4a1c-9e3b-7f5d1c3a9e02".to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: None,
+      chunk_num: None,
+      chunk_checksum: None,
+      cancel: None
+    };
+    let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+    write_headers(&headers, &mut client).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(response, NO_LEASE_MESSAGE.to_vec());
+
+    assert!(handle.shutdown(Duration::from_secs(5)).is_ok());
+  }
+
+  #[test]
+  fn rejects_bytes_past_the_frame() {
+    let handle = spawn_server(event_config("rjchunker_event_frame_test")).unwrap();
+
+    let mut frame = chunk_headers(0);
+    frame.extend_from_slice(&[1u8; MIN_CHUNK_BYTES as usize + 1]);
+    let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+    client.write_all(&frame).unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(response, ERR_MESSAGE.to_vec());
+    assert_eq!(handle.cache.load.in_flight_bytes(), 0);
+
+    assert!(handle.shutdown(Duration::from_secs(5)).is_ok());
+  }
+
+  #[test]
+  fn answers_retry_before_reading_the_body() {
+    let config = ServerConfig { max_in_flight_bytes: MIN_CHUNK_BYTES as u64, ..event_config("rjchunker_event_admit_test") };
+    let handle = spawn_server(config).unwrap();
+    let cache = handle.cache.clone();
+
+    // admitted, and holds the whole budget while its body is awaited
+    let mut first = TcpStream::connect(handle.local_addr()).unwrap();
+    first.write_all(&chunk_headers(0)).unwrap();
+    let started = Instant::now();
+    while cache.load.in_flight_bytes() == 0 {
+      assert!(started.elapsed() < Duration::from_secs(5), "First request wasn't admitted");
+      std::thread::sleep(Duration::from_millis(10));
+    }
+
+    // sent in one go, the body is left unread
+    let mut second = TcpStream::connect(handle.local_addr()).unwrap();
+    let mut frame = chunk_headers(1);
+    frame.extend_from_slice(&[1u8; MIN_CHUNK_BYTES as usize]);
+    second.write_all(&frame).unwrap();
+    let mut response = vec![0u8; 1 + admission::RETRY_HINT_BYTES];
+    second.read_exact(&mut response).unwrap();
+    assert_eq!(response[0], RETRY_MESSAGE[0]);
+
+    // the first client is turned away on shutdown, and its bytes given back
+    assert!(handle.shutdown(Duration::from_secs(5)).is_ok());
+    let mut response = Vec::new();
+    first.read_to_end(&mut response).unwrap();
+    assert_eq!(response[0], RETRY_MESSAGE[0]);
+    assert_eq!(cache.load.in_flight_bytes(), 0);
+  }
+}
@@ -0,0 +1,160 @@
+use std::net::{SocketAddr, TcpStream, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crossbeam_channel::{bounded, Sender, Receiver, TryRecvError, RecvTimeoutError};
+
+use crate::{Cache, store};
+use crate::errors::Errors;
+use crate::config::ServerConfig;
+
+/// Closes once the server starts shutting down. Only the stages
+/// that don't stop when their queue closes need to watch it.
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal {
+  shutdown_r: Receiver<()>
+}
+
+/// The signal closes when the sender is dropped.
+pub fn signal() -> (Sender<()>, ShutdownSignal) {
+  let (shutdown_s, shutdown_r) = bounded(0);
+  return (shutdown_s, ShutdownSignal { shutdown_r });
+}
+
+impl ShutdownSignal {
+  pub fn is_set(&self) -> bool {
+    return matches!(self.shutdown_r.try_recv(), Err(TryRecvError::Disconnected));
+  }
+
+  /// Sleeps for the duration. True if cut short by shutdown.
+  pub fn wait(&self, duration: Duration) -> bool {
+    return matches!(self.shutdown_r.recv_timeout(duration), Err(RecvTimeoutError::Disconnected));
+  }
+}
+
+/// A running server. Dropping the handle leaves it running.
+pub struct ServerHandle {
+  local_addr: SocketAddr,
+  pub(crate) cache: Arc<Cache>,
+  shutdown_s: Option<Sender<()>>,
+  /// every stage holds a sender, so this
+  /// disconnects once they have all stopped
+  done_r: Receiver<()>
+}
+
+impl ServerHandle {
+  pub fn new(local_addr: SocketAddr, cache: Arc<Cache>, shutdown_s: Sender<()>, done_r: Receiver<()>) -> ServerHandle {
+    return ServerHandle {
+      local_addr,
+      cache,
+      shutdown_s: Some(shutdown_s),
+      done_r
+    };
+  }
+
+  /// Address the server is accepting on.
+  pub fn local_addr(&self) -> SocketAddr {
+    return self.local_addr;
+  }
+
+  /// Takes on the limits of the config, the ones kept in
+  /// admission::Limits. Anything else needs a restart.
+  pub fn reload(&self, config: &ServerConfig) {
+    self.cache.limits.set(config);
+  }
+
+  /// Blocks until the server stops, which
+  /// won't happen without a shutdown.
+  pub fn wait(self) {
+    while self.done_r.recv().is_ok() {}
+  }
+
+  /// Stops accepting connections, lets the requests already
+  /// accepted finish writing, then persists the leases so the
+  /// uploads can resume after a restart. Fails if the stages
+  /// haven't stopped within the timeout.
+  pub fn shutdown(mut self, timeout: Duration) -> Result<(), Errors> {
+    drop(self.shutdown_s.take());
+
+    // the accept loop only sees the signal
+    // once it's woken by a connection
+    let mut wake = self.local_addr;
+    if wake.ip().is_unspecified() {
+      match wake {
+        SocketAddr::V4(_) => wake.set_ip(Ipv4Addr::LOCALHOST.into()),
+        SocketAddr::V6(_) => wake.set_ip(Ipv6Addr::LOCALHOST.into())
+      };
+    }
+    TcpStream::connect_timeout(&wake, timeout).ok();
+
+    match self.done_r.recv_timeout(timeout) {
+      Err(RecvTimeoutError::Disconnected) => (),
+      _ => return Err(Errors::TimeoutError("Server didn't stop within the shutdown timeout".to_string()))
+    };
+
+    return store::persist(&self.cache);
+  }
+}
+
+/// Runs the stage on its own thread, holding
+/// onto a done sender until it returns.
+pub fn spawn_stage<F: FnOnce() + Send + 'static>(done_s: &Sender<()>, stage: F) {
+  let done_s = done_s.clone();
+  thread::spawn(move || {
+    stage();
+    drop(done_s);
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::spawn_server;
+  use crate::config::ServerConfig;
+  use std::time::Duration;
+
+  #[test]
+  fn shuts_down_within_the_timeout() {
+    let config = ServerConfig::builder()
+      .bind_address("127.0.0.1:0")
+      .storage_dir(std::env::temp_dir().join("rjchunker_handle_test"))
+      .build()
+      .unwrap();
+    let handle = spawn_server(config).unwrap();
+    assert!(handle.local_addr().port() > 0);
+    assert!(handle.shutdown(Duration::from_secs(5)).is_ok());
+  }
+
+  #[test]
+  fn checks_configs_put_together_without_the_builder() {
+    let config = ServerConfig {
+      bind_address: "127.0.0.1:0".to_string(),
+      storage_dir: std::env::temp_dir().join("rjchunker_handle_check_test"),
+      quarantine_dir: "complete".into(),
+      ..ServerConfig::default()
+    };
+    assert!(spawn_server(config).is_err());
+  }
+
+  #[test]
+  fn reloads_the_limits() {
+    let config = ServerConfig::builder()
+      .bind_address("127.0.0.1:0")
+      .storage_dir(std::env::temp_dir().join("rjchunker_handle_reload_test"))
+      .max_leases(8)
+      .build()
+      .unwrap();
+    let handle = spawn_server(config.clone()).unwrap();
+    assert_eq!(handle.cache.limits.max_leases(), 8);
+
+    let config = ServerConfig {
+      max_leases: 2,
+      max_lease_writers: 1,
+      ..config
+    };
+    handle.reload(&config);
+    assert_eq!(handle.cache.limits.max_leases(), 2);
+    assert_eq!(handle.cache.limits.max_lease_writers(), 1);
+
+    assert!(handle.shutdown(Duration::from_secs(5)).is_ok());
+  }
+}
@@ -1,9 +1,10 @@
 pub mod read;
+pub mod write;
 
 pub const MIN_CHUNK_BYTES: u32 = 1000; // 1 KB
 pub const MAX_CHUNK_BYTES: u32 = 1000000; // 1 MB
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Headers {
   pub header_type: HeaderType,
   pub lease_id: Option<String>,
@@ -12,6 +13,8 @@ pub struct Headers {
   pub file_length: Option<u32>,
   pub chunk_length: Option<u32>,
   pub chunk_num: Option<u32>,
+  /// CRC32C of the chunk body
+  pub chunk_checksum: Option<u32>,
   pub cancel: Option<bool>
 }
 
@@ -21,12 +24,13 @@ impl Headers {
   }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HeaderType {
   LEASE,
   CHUNK,
   FINAL,
   CANCEL,
+  STATUS,
   ERROR,
 }
 
@@ -53,22 +57,23 @@ impl Headers {
       && self.file_length.is_none()
   }
 
-  /// true if the chunk_length equals the amount of bytes
-  /// left in the lease. Keep in might a final type is also
-  /// a chunk type.
-  pub fn is_final_type(&self, bytes_left: &u32) -> bool {
-    if let Some(chunk_length) = self.chunk_length {
-      return bytes_left == &chunk_length;
-    };
-
-    return false;
-  }
-
   /// true if cancel is specified
   pub fn is_cancel_type(&self) -> bool {
     return self.cancel.is_some()
       && self.lease_id.is_some()
   }
+
+  /// true if only the lease_id is specified
+  pub fn is_status_type(&self) -> bool {
+    return self.lease_id.is_some()
+      && self.checksum.is_none()
+      && self.file_name.is_none()
+      && self.file_length.is_none()
+      && self.chunk_length.is_none()
+      && self.chunk_num.is_none()
+      && self.chunk_checksum.is_none()
+      && self.cancel.is_none()
+  }
 }
 
 
@@ -1,8 +1,8 @@
-use std::net::TcpStream;
+use std::io::Read;
 
 use crate::errors::Errors;
 use crate::io::{read, util};
-use crate::headers::{Headers, HeaderType, MIN_CHUNK_BYTES, MAX_CHUNK_BYTES};
+use crate::headers::{Headers, HeaderType, MAX_CHUNK_BYTES};
 
 pub const UUID_BYTES: u32 = 16;
 pub const UUID_POS: u8 = 0;
@@ -24,8 +24,62 @@ pub const CHUNK_NUM_POS: u8 = 5;
 
 pub const CANCEL_POS: u8 = 6;
 
-pub fn read_headers(client: &mut TcpStream) -> Result<Headers, Errors> {
-  let params = read::pluck_stream(client, &1)?[0];
+pub const CHUNK_CHECKSUM_BYTES: u32 = 4;
+pub const CHUNK_CHECKSUM_POS: u8 = 7;
+
+/// Fields in the order they follow the params byte.
+const FIELDS: [(u8, u32); 7] = [
+  (UUID_POS, UUID_BYTES),
+  (CHECKSUM_POS, CHECKSUM_BYTES),
+  (FILE_NAME_POS, FILE_NAME_BYTES),
+  (FILE_LENGTH_POS, FILE_LENGTH_BYTES),
+  (CHUNK_LENGTH_POS, CHUNK_LENGTH_BYTES),
+  (CHUNK_NUM_POS, CHUNK_NUM_BYTES),
+  (CHUNK_CHECKSUM_POS, CHUNK_CHECKSUM_BYTES),
+];
+
+/// Bytes of headers that follow the params byte, params included.
+pub fn header_length(params: u8) -> usize {
+  return 1 + FIELDS.iter()
+    .filter(|(pos, _)| util::bit_at(params, *pos))
+    .map(|(_, bytes)| *bytes as usize)
+    .sum::<usize>();
+}
+
+/// Length of the whole request (headers and chunk body) once
+/// enough of it is buffered to tell, for backends that read
+/// requests in pieces.
+pub fn frame_length(buffer: &[u8]) -> Result<Option<usize>, Errors> {
+  let params = match buffer.first() {
+    Some(p) => *p,
+    None => return Ok(None)
+  };
+
+  let header_length = header_length(params);
+  if buffer.len() < header_length {
+    return Ok(None);
+  }
+
+  if !util::bit_at(params, CHUNK_LENGTH_POS) {
+    return Ok(Some(header_length));
+  }
+
+  let offset = 1 + FIELDS.iter()
+    .take_while(|(pos, _)| *pos != CHUNK_LENGTH_POS)
+    .filter(|(pos, _)| util::bit_at(params, *pos))
+    .map(|(_, bytes)| *bytes as usize)
+    .sum::<usize>();
+  let chunk_length = util::read_u32(&buffer[offset..offset + CHUNK_LENGTH_BYTES as usize].to_vec())?;
+
+  if chunk_length > MAX_CHUNK_BYTES {
+    return Err(Errors::InvalidRequest("requests chunk length to large".to_string()));
+  }
+
+  return Ok(Some(header_length + chunk_length as usize));
+}
+
+pub fn read_headers<R: Read>(client: &mut R) -> Result<Headers, Errors> {
+  let params = read::read_exact(client, 1)?[0];
 
   let mut headers = Headers {
     header_type: HeaderType::ERROR,
@@ -35,53 +89,41 @@ pub fn read_headers(client: &mut TcpStream) -> Result<Headers, Errors> {
     file_length: None,
     chunk_length: None,
     chunk_num: None,
+    chunk_checksum: None,
     cancel: None
   };
 
   if util::bit_at(params, UUID_POS) {
-    let data = read::pluck_stream(client, &UUID_BYTES)?;
-    if data.len() != UUID_BYTES as usize {
-      return Err(Errors::ReadError("invalid uuid length from headers".to_string()));
-    }
-    headers.lease_id = Some(String::from_utf8_lossy(&data).to_string());
+    let data = read::read_exact(client, UUID_BYTES as usize)?;
+    headers.lease_id = Some(util::read_uuid(&data)?);
   }
 
   if util::bit_at(params, CHECKSUM_POS) {
-    let data = read::pluck_stream(client, &CHECKSUM_BYTES)?;
-    if data.len() != CHECKSUM_BYTES as usize {
-      return Err(Errors::ReadError("invalid checksum length from headers".to_string()));
-    }
-    headers.checksum = Some(String::from_utf8_lossy(&data).to_string());
+    let data = read::read_exact(client, CHECKSUM_BYTES as usize)?;
+    headers.checksum = Some(util::read_padded_string(&data));
   }
 
   if util::bit_at(params, FILE_NAME_POS) {
-    let data = read::pluck_stream(client, &FILE_NAME_BYTES)?;
-    if data.len() != FILE_NAME_BYTES as usize {
-      return Err(Errors::ReadError("invalid file_name length from headers".to_string()));
-    }
-    headers.file_name = Some(String::from_utf8_lossy(&data).to_string());
+    let data = read::read_exact(client, FILE_NAME_BYTES as usize)?;
+    headers.file_name = Some(util::read_padded_string(&data));
   }
 
   if util::bit_at(params, FILE_LENGTH_POS) {
-    let data = read::pluck_stream(client, &FILE_LENGTH_BYTES)?;
-    if data.len() != FILE_LENGTH_BYTES as usize {
-      return Err(Errors::ReadError("invalid file_length length from headers".to_string()));
-    }
+    let data = read::read_exact(client, FILE_LENGTH_BYTES as usize)?;
     headers.file_length = Some(util::read_u32(&data)?);
   }
 
   if util::bit_at(params, CHUNK_LENGTH_POS) {
-    let data = read::pluck_stream(client, &CHUNK_LENGTH_BYTES)?;
-    if data.len() != CHUNK_LENGTH_BYTES as usize {
-      return Err(Errors::ReadError("invalid chunk_length length from headers".to_string()));
-    }
+    let data = read::read_exact(client, CHUNK_LENGTH_BYTES as usize)?;
     let chunk_length = util::read_u32(&data)?;
 
     if chunk_length > MAX_CHUNK_BYTES {
       return Err(Errors::InvalidRequest("requests chunk length to large".to_string()));
     }
 
-    if chunk_length < MIN_CHUNK_BYTES {
+    // shorter than MIN_CHUNK_BYTES is allowed for the last
+    // chunk of a file, which the process stage checks.
+    if chunk_length == 0 {
       return Err(Errors::InvalidRequest("requests chunk length to small".to_string()));
     }
 
@@ -89,10 +131,7 @@ pub fn read_headers(client: &mut TcpStream) -> Result<Headers, Errors> {
   }
 
   if util::bit_at(params, CHUNK_NUM_POS) {
-    let data = read::pluck_stream(client, &CHUNK_NUM_BYTES)?;
-    if data.len() != CHUNK_NUM_BYTES as usize {
-      return Err(Errors::ReadError("invalid chunk_num length from headers".to_string()));
-    }
+    let data = read::read_exact(client, CHUNK_NUM_BYTES as usize)?;
     headers.chunk_num = Some(util::read_u32(&data)?);
   }
 
@@ -100,6 +139,81 @@ pub fn read_headers(client: &mut TcpStream) -> Result<Headers, Errors> {
     headers.cancel = Some(true);
   }
 
+  if util::bit_at(params, CHUNK_CHECKSUM_POS) {
+    let data = read::read_exact(client, CHUNK_CHECKSUM_BYTES as usize)?;
+    headers.chunk_checksum = Some(util::read_u32(&data)?);
+  }
+
   return Ok(headers);
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn padded(value: &str, length: u32) -> Vec<u8> {
+    let mut data = value.as_bytes().to_vec();
+    data.resize(length as usize, 0);
+    return data;
+  }
+
+  #[test]
+  fn reads_lease_headers() {
+    let mut frame = vec![0b0011_1110u8];
+    frame.extend(padded("sha256:abc", CHECKSUM_BYTES));
+    frame.extend(padded("photo.jpg", FILE_NAME_BYTES));
+    frame.extend(&5000u32.to_le_bytes());
+    frame.extend(&2000u32.to_le_bytes());
+    frame.extend(&0u32.to_le_bytes());
+
+    let headers = read_headers(&mut Cursor::new(frame)).unwrap();
+    assert!(headers.is_lease_type());
+    assert_eq!(headers.checksum.unwrap(), "sha256:abc");
+    assert_eq!(headers.file_name.unwrap(), "photo.jpg");
+    assert_eq!(headers.file_length, Some(5000));
+    assert_eq!(headers.chunk_length, Some(2000));
+    assert_eq!(headers.chunk_num, Some(0));
+  }
+
+  #[test]
+  fn reads_lease_id_as_uuid() {
+    let lease_id = "936da01f-9abd-4d9d-80c7-02af85c822a8";
+    let mut frame = vec![0b1011_0001u8];
+    frame.extend(util::uuid_bytes(lease_id).unwrap());
+    frame.extend(&2000u32.to_le_bytes());
+    frame.extend(&1u32.to_le_bytes());
+    frame.extend(&7u32.to_le_bytes());
+
+    let headers = read_headers(&mut Cursor::new(frame)).unwrap();
+    assert!(headers.is_chunk_type());
+    assert_eq!(headers.lease_id.unwrap(), lease_id);
+    assert_eq!(headers.chunk_checksum, Some(7));
+  }
+
+  #[test]
+  fn knows_the_frame_length() {
+    let mut frame = vec![0b0011_0001u8];
+    frame.extend(&[0u8; UUID_BYTES as usize]);
+    assert_eq!(frame_length(&frame).unwrap(), None);
+
+    frame.extend(&1500u32.to_le_bytes());
+    frame.extend(&3u32.to_le_bytes());
+    assert_eq!(header_length(frame[0]), 25);
+    assert_eq!(frame_length(&frame).unwrap(), Some(25 + 1500));
+
+    assert_eq!(frame_length(&[0b0100_0001u8]).unwrap(), None);
+    assert_eq!(frame_length(&[]).unwrap(), None);
+  }
+
+  #[test]
+  fn rejects_truncated_headers() {
+    let mut frame = vec![0b0000_1000u8];
+    frame.extend(&[1, 2]);
+
+    match read_headers(&mut Cursor::new(frame)) {
+      Err(Errors::ReadLengthError(_)) => (),
+      r => panic!("expected short read, got {:?}", r)
+    };
+  }
+}
@@ -0,0 +1,163 @@
+use std::io::Write;
+
+use crate::errors::Errors;
+use crate::io::util;
+use crate::headers::{Headers, MAX_CHUNK_BYTES};
+use crate::headers::read::{
+  UUID_POS, CHECKSUM_BYTES, CHECKSUM_POS, FILE_NAME_BYTES, FILE_NAME_POS, FILE_LENGTH_POS,
+  CHUNK_LENGTH_POS, CHUNK_NUM_POS, CANCEL_POS, CHUNK_CHECKSUM_POS
+};
+
+/// Writes the headers the way `read_headers` reads them: the
+/// params byte for the fields that are set, then each field.
+/// Nothing is written if a field can't be encoded.
+pub fn write_headers<W: Write>(headers: &Headers, client: &mut W) -> Result<(), Errors> {
+  let frame = encode_headers(headers)?;
+
+  return match client.write_all(&frame) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write headers".to_string()))
+  };
+}
+
+pub fn encode_headers(headers: &Headers) -> Result<Vec<u8>, Errors> {
+  let mut params = 0u8;
+  let mut frame = vec![0u8];
+
+  if let Some(lease_id) = &headers.lease_id {
+    params |= 1 << UUID_POS;
+    frame.extend(util::uuid_bytes(lease_id)?);
+  }
+
+  if let Some(checksum) = &headers.checksum {
+    params |= 1 << CHECKSUM_POS;
+    frame.extend(padded("checksum", checksum, CHECKSUM_BYTES)?);
+  }
+
+  if let Some(file_name) = &headers.file_name {
+    params |= 1 << FILE_NAME_POS;
+    frame.extend(padded("file_name", file_name, FILE_NAME_BYTES)?);
+  }
+
+  if let Some(file_length) = headers.file_length {
+    params |= 1 << FILE_LENGTH_POS;
+    frame.extend(&file_length.to_le_bytes());
+  }
+
+  if let Some(chunk_length) = headers.chunk_length {
+    if chunk_length == 0 || chunk_length > MAX_CHUNK_BYTES {
+      return Err(Errors::InvalidRequest(format!("chunk_length must be between 1 and {}", MAX_CHUNK_BYTES)));
+    }
+
+    params |= 1 << CHUNK_LENGTH_POS;
+    frame.extend(&chunk_length.to_le_bytes());
+  }
+
+  if let Some(chunk_num) = headers.chunk_num {
+    params |= 1 << CHUNK_NUM_POS;
+    frame.extend(&chunk_num.to_le_bytes());
+  }
+
+  // a flag, there are no bytes to it
+  if let Some(true) = headers.cancel {
+    params |= 1 << CANCEL_POS;
+  }
+
+  if let Some(chunk_checksum) = headers.chunk_checksum {
+    params |= 1 << CHUNK_CHECKSUM_POS;
+    frame.extend(&chunk_checksum.to_le_bytes());
+  }
+
+  frame[0] = params;
+  return Ok(frame);
+}
+
+/// Zero pads the value out to the field length. Zeros end
+/// the value when read, so the value can't contain any.
+fn padded(field: &str, value: &str, length: u32) -> Result<Vec<u8>, Errors> {
+  let mut data = value.as_bytes().to_vec();
+
+  if data.len() > length as usize {
+    return Err(Errors::InvalidRequest(format!("{} is longer than {} bytes", field, length)));
+  }
+
+  if data.contains(&0) {
+    return Err(Errors::InvalidRequest(format!("{} can't contain zero bytes", field)));
+  }
+
+  data.resize(length as usize, 0);
+  return Ok(data);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use crate::headers::HeaderType;
+  use crate::headers::read::read_headers;
+
+  fn headers() -> Headers {
+    return Headers {
+      header_type: HeaderType::ERROR,
+      lease_id: None,
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: None,
+      chunk_num: None,
+      chunk_checksum: None,
+      cancel: None
+    };
+  }
+
+  fn round_trip(headers: &Headers) -> Headers {
+    let mut frame = Vec::new();
+    write_headers(headers, &mut frame).unwrap();
+    return read_headers(&mut Cursor::new(frame)).unwrap();
+  }
+
+  #[test]
+  fn round_trips_with_read_headers() {
+    let lease = Headers {
+      checksum: Some("sha256:abc".to_string()),
+      file_name: Some("photo.jpg".to_string()),
+      file_length: Some(5000),
+      chunk_length: Some(2000),
+      chunk_num: Some(0),
+      chunk_checksum: Some(42),
+      ..headers()
+    };
+    assert_eq!(round_trip(&lease), lease);
+
+    let chunk = Headers {
+      lease_id: Some("936da01f-9abd-4d9d-80c7-02af85c822a8".to_string()),
+      chunk_length: Some(1000),
+      chunk_num: Some(3),
+      ..headers()
+    };
+    assert_eq!(round_trip(&chunk), chunk);
+
+    let cancel = Headers {
+      lease_id: Some("936da01f-9abd-4d9d-80c7-02af85c822a8".to_string()),
+      cancel: Some(true),
+      ..headers()
+    };
+    assert_eq!(round_trip(&cancel), cancel);
+  }
+
+  #[test]
+  fn rejects_fields_that_cant_be_encoded() {
+    let mut frame = Vec::new();
+
+    let long_name = Headers { file_name: Some("a".repeat(FILE_NAME_BYTES as usize + 1)), ..headers() };
+    assert!(write_headers(&long_name, &mut frame).is_err());
+
+    let bad_lease = Headers { lease_id: Some("not a uuid".to_string()), ..headers() };
+    assert!(write_headers(&bad_lease, &mut frame).is_err());
+
+    let empty_chunk = Headers { chunk_length: Some(0), ..headers() };
+    assert!(write_headers(&empty_chunk, &mut frame).is_err());
+
+    assert!(frame.is_empty());
+  }
+}
@@ -1,48 +1,122 @@
 use std::io::{Read, ErrorKind};
-use std::net::{TcpStream};
+use std::time::Instant;
 
 use crate::errors::Errors;
 
-pub fn read_stream(client: &mut TcpStream, byte_amount: usize, chunk: usize) -> Result<Vec<u8>, Errors> {
-  let mut data = Vec::with_capacity(byte_amount);
+/// Reads exactly byte_amount bytes from the stream.
+///
+/// A stream that ends before any bytes are read is a clean close
+/// (`ReadClosedError`), one that ends partway through is a short
+/// read (`ReadLengthError`) and one that stalls partway through
+/// timed out (`TimeoutError`). If no data is available yet and
+/// nothing was consumed, the read can be retried (`ReadRetryError`).
+pub fn read_exact<R: Read>(client: &mut R, byte_amount: usize) -> Result<Vec<u8>, Errors> {
+  return read_exact_within(client, byte_amount, None);
+}
+
+/// Same as `read_exact`, but the bytes also have to arrive before
+/// the deadline. Keeps a client trickling in data from holding
+/// onto the connection.
+pub fn read_exact_within<R: Read>(client: &mut R, byte_amount: usize, deadline: Option<Instant>) -> Result<Vec<u8>, Errors> {
+  let mut data = vec![0u8; byte_amount];
   let mut read_bytes = 0;
 
   while read_bytes < byte_amount {
-    let mut buffer = Vec::with_capacity(chunk);
-    let length = match client.read(&mut buffer) {
-      Ok(l) => l,
+    match client.read(&mut data[read_bytes..]) {
+      Ok(0) => {
+        if read_bytes == 0 {
+          return Err(Errors::ReadClosedError);
+        }
+        return Err(Errors::ReadLengthError(format!("Stream ended after {} of {} bytes", read_bytes, byte_amount)));
+      },
+      Ok(length) => {
+        read_bytes += length;
+
+        if let Some(deadline) = deadline {
+          if read_bytes < byte_amount && Instant::now() > deadline {
+            return Err(Errors::TimeoutError(format!("Too slow, read {} of {} bytes", read_bytes, byte_amount)));
+          }
+        }
+      },
       Err(e) => {
-        println!("{}", e);
-        return Err(Errors::ReadError("Failed to read bytes".to_string()));
+        match e.kind() {
+          ErrorKind::Interrupted => continue,
+          ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            if read_bytes == 0 {
+              return Err(Errors::ReadRetryError);
+            }
+            return Err(Errors::TimeoutError(format!("Stream stalled after {} of {} bytes", read_bytes, byte_amount)));
+          },
+          _ => return Err(Errors::ReadError(format!("Failed to read bytes: {}", e)))
+        };
       }
     };
-    read_bytes += length;
+  }
+
+  return Ok(data);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{self, Cursor};
 
-    data.append(&mut buffer.to_vec());
+  /// Hands out one step at a time: bytes or an error kind.
+  struct Steps(Vec<Result<Vec<u8>, ErrorKind>>);
 
-    if length <= 0 {
-      break;
+  impl Read for Steps {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if self.0.is_empty() {
+        return Ok(0);
+      }
+      return match self.0.remove(0) {
+        Ok(bytes) => {
+          buf[..bytes.len()].copy_from_slice(&bytes);
+          Ok(bytes.len())
+        },
+        Err(kind) => Err(io::Error::new(kind, "step"))
+      };
     }
   }
 
-  let slice = &data[0..byte_amount];
+  #[test]
+  fn reads_exact_amount_across_partial_reads() {
+    let mut steps = Steps(vec![Ok(vec![1, 2]), Err(ErrorKind::Interrupted), Ok(vec![3]), Ok(vec![4])]);
+    assert_eq!(read_exact(&mut steps, 4).unwrap(), vec![1, 2, 3, 4]);
 
-  return Ok(slice.to_vec());
-}
+    let mut cursor = Cursor::new(vec![5, 6, 7]);
+    assert_eq!(read_exact(&mut cursor, 2).unwrap(), vec![5, 6]);
+  }
 
-/// Reads no more, but potentially less data than the byte_amount from the stream.
-pub fn pluck_stream(client: &mut TcpStream, byte_amount: &u32) -> Result<Vec<u8>, Errors> {
-  let mut data = Vec::with_capacity(*byte_amount as usize);
+  #[test]
+  fn distinguishes_close_short_read_and_retry() {
+    match read_exact(&mut Cursor::new(vec![]), 4) {
+      Err(Errors::ReadClosedError) => (),
+      r => panic!("expected closed, got {:?}", r)
+    };
+    match read_exact(&mut Cursor::new(vec![1, 2]), 4) {
+      Err(Errors::ReadLengthError(_)) => (),
+      r => panic!("expected short read, got {:?}", r)
+    };
+    match read_exact(&mut Steps(vec![Err(ErrorKind::WouldBlock)]), 4) {
+      Err(Errors::ReadRetryError) => (),
+      r => panic!("expected retry, got {:?}", r)
+    };
+    match read_exact(&mut Steps(vec![Ok(vec![1]), Err(ErrorKind::WouldBlock)]), 4) {
+      Err(Errors::TimeoutError(_)) => (),
+      r => panic!("expected timeout, got {:?}", r)
+    };
+  }
 
-  match client.read(&mut data) {
-    Ok(l) => l,
-    Err(e) => {
-      return match e.kind() {
-        ErrorKind::Interrupted => Err(Errors::ReadRetryError),
-        _ => Err(Errors::ReadError("Failed to pluck bytes".to_string()))
-      };
-    }
-  };
+  #[test]
+  fn times_out_past_the_deadline() {
+    let deadline = Some(Instant::now());
+    let mut steps = Steps(vec![Ok(vec![1]), Ok(vec![2])]);
 
-  return Ok(data);
-}
\ No newline at end of file
+    match read_exact_within(&mut steps, 2, deadline) {
+      Err(Errors::TimeoutError(_)) => (),
+      r => panic!("expected timeout, got {:?}", r)
+    };
+    assert!(read_exact_within(&mut Cursor::new(vec![1, 2]), 2, deadline).is_ok());
+  }
+}
@@ -1,6 +1,7 @@
 use byteorder::ReadBytesExt;
 use std::io::Cursor;
 use byteorder::{LittleEndian};
+use uuid::Uuid;
 
 use crate::errors::Errors;
 
@@ -15,6 +16,28 @@ pub fn bit_at(byte: u8, n: u8) -> bool {
 pub fn read_u32(bytes: &Vec<u8>) -> Result<u32, Errors> {
   return match Cursor::new(bytes).read_u32::<LittleEndian>() {
     Ok(c) => Ok(c),
-    Err(e) => Err(Errors::ParseError("Failed to parse to u32".to_string()))
+    Err(_) => Err(Errors::ParseError("Failed to parse to u32".to_string()))
   };
+}
+
+/// Hyphenated lease id from the 16 raw uuid bytes.
+pub fn read_uuid(bytes: &[u8]) -> Result<String, Errors> {
+  return match Uuid::from_slice(bytes) {
+    Ok(u) => Ok(u.to_string()),
+    Err(_) => Err(Errors::ParseError("Failed to parse uuid".to_string()))
+  };
+}
+
+/// The 16 raw uuid bytes of a hyphenated lease id.
+pub fn uuid_bytes(id: &str) -> Result<Vec<u8>, Errors> {
+  return match Uuid::parse_str(id) {
+    Ok(u) => Ok(u.as_bytes().to_vec()),
+    Err(_) => Err(Errors::ParseError("Failed to parse uuid".to_string()))
+  };
+}
+
+/// String from a fixed width field, without its zero padding.
+pub fn read_padded_string(bytes: &[u8]) -> String {
+  let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+  return String::from_utf8_lossy(&bytes[..end]).to_string();
 }
\ No newline at end of file
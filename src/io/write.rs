@@ -2,59 +2,152 @@ use std::io::Write;
 use std::net::{TcpStream};
 
 use crate::errors::Errors;
+use crate::admission::RetryHint;
 
-pub const OK_MESSAGE: [u8; 1] = [1 as u8];
-pub const ERR_MESSAGE: [u8; 1] = [2 as u8];
-pub const CONTINUE_MESSAGE: [u8; 1] = [3 as u8];
-pub const RETRY_MESSAGE: [u8; 1] = [4 as u8];
-pub const NO_LEASE_MESSAGE: [u8; 1] = [5 as u8];
-pub const LEASE_IN_USE_MESSAGE: [u8; 1] = [6 as u8];
+pub const OK_MESSAGE: [u8; 1] = [1];
+pub const ERR_MESSAGE: [u8; 1] = [2];
+pub const CONTINUE_MESSAGE: [u8; 1] = [3];
+pub const RETRY_MESSAGE: [u8; 1] = [4];
+pub const NO_LEASE_MESSAGE: [u8; 1] = [5];
+pub const LEASE_IN_USE_MESSAGE: [u8; 1] = [6];
+pub const CHECKSUM_MISMATCH_MESSAGE: [u8; 1] = [7];
+pub const DUPLICATE_CHUNK_MESSAGE: [u8; 1] = [8];
+pub const OUT_OF_RANGE_MESSAGE: [u8; 1] = [9];
+pub const FILE_EXISTS_MESSAGE: [u8; 1] = [10];
 
 pub fn write_string(client: &mut TcpStream, message: &String) -> Result<(), Errors> {
   return match client.write_all(message.as_bytes()) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write message".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write message".to_string()))
+  };
+}
+
+pub fn write_bytes(client: &mut TcpStream, data: &[u8]) -> Result<(), Errors> {
+  return match client.write_all(data) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write bytes".to_string()))
   };
 }
 
 pub fn write_ok(client: &mut TcpStream) -> Result<(), Errors> {
   return match client.write_all(&OK_MESSAGE) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write ok".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write ok".to_string()))
   };
 }
 
+/// OK followed by the response, if there is one.
+pub fn write_response(client: &mut TcpStream, response: &Option<Vec<u8>>) -> Result<(), Errors> {
+  write_ok(client)?;
+
+  if let Some(response) = response {
+    write_bytes(client, response)?;
+  }
+
+  return Ok(());
+}
+
 pub fn write_err(client: &mut TcpStream) -> Result<(), Errors> {
   return match client.write_all(&ERR_MESSAGE) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write err".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write err".to_string()))
   };
 }
 
 pub fn write_continue(client: &mut TcpStream) -> Result<(), Errors> {
   return match client.write_all(&CONTINUE_MESSAGE) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write continue".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write continue".to_string()))
   };
 }
 
-pub fn write_retry(client: &mut TcpStream) -> Result<(), Errors> {
-  return match client.write_all(&RETRY_MESSAGE) {
+/// RETRY followed by the hint of when to retry.
+pub fn write_retry(client: &mut TcpStream, hint: &RetryHint) -> Result<(), Errors> {
+  let mut message = RETRY_MESSAGE.to_vec();
+  message.extend(hint.to_bytes());
+
+  return match client.write_all(&message) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write retry".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write retry".to_string()))
   };
 }
 
 pub fn write_no_lease(client: &mut TcpStream) -> Result<(), Errors> {
   return match client.write_all(&NO_LEASE_MESSAGE) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write no lease".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write no lease".to_string()))
   };
 }
 
 pub fn write_lease_in_use(client: &mut TcpStream) -> Result<(), Errors> {
   return match client.write_all(&LEASE_IN_USE_MESSAGE) {
     Ok(()) => Ok(()),
-    Err(e) => Err(Errors::WriteError("Failed to write lease in use".to_string()))
+    Err(_) => Err(Errors::WriteError("Failed to write lease in use".to_string()))
+  };
+}
+
+pub fn write_checksum_mismatch(client: &mut TcpStream) -> Result<(), Errors> {
+  return match client.write_all(&CHECKSUM_MISMATCH_MESSAGE) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write checksum mismatch".to_string()))
+  };
+}
+
+pub fn write_duplicate_chunk(client: &mut TcpStream) -> Result<(), Errors> {
+  return match client.write_all(&DUPLICATE_CHUNK_MESSAGE) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write duplicate chunk".to_string()))
+  };
+}
+
+pub fn write_out_of_range(client: &mut TcpStream) -> Result<(), Errors> {
+  return match client.write_all(&OUT_OF_RANGE_MESSAGE) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write out of range".to_string()))
+  };
+}
+
+pub fn write_file_exists(client: &mut TcpStream) -> Result<(), Errors> {
+  return match client.write_all(&FILE_EXISTS_MESSAGE) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write file exists".to_string()))
+  };
+}
+
+/// Responds with the message for the error. Errors without
+/// a message of their own are sent as ERR.
+pub fn write_error(client: &mut TcpStream, error: &Errors) -> Result<(), Errors> {
+  return match client.write_all(&error_bytes(error)) {
+    Ok(()) => Ok(()),
+    Err(_) => Err(Errors::WriteError("Failed to write error".to_string()))
+  };
+}
+
+/// The message for the error, followed by the retry hint if
+/// it's RETRY. What every backend responds to the error with.
+pub fn error_bytes(error: &Errors) -> Vec<u8> {
+  let mut data = error_message(error).to_vec();
+
+  if data == RETRY_MESSAGE {
+    let hint = match error {
+      Errors::BusyError(hint) => *hint,
+      _ => RetryHint::default()
+    };
+    data.extend(hint.to_bytes());
+  }
+
+  return data;
+}
+
+/// The message write_error responds to the error with.
+pub fn error_message(error: &Errors) -> [u8; 1] {
+  return match error {
+    Errors::ChecksumError(_) => CHECKSUM_MISMATCH_MESSAGE,
+    Errors::ChunkChecksumError(_) | Errors::TimeoutError(_) | Errors::BusyError(_) => RETRY_MESSAGE,
+    Errors::OutOfRangeError(_) => OUT_OF_RANGE_MESSAGE,
+    Errors::NoLeaseError(_) => NO_LEASE_MESSAGE,
+    Errors::LeaseInUseError(_) => LEASE_IN_USE_MESSAGE,
+    Errors::FileExistsError(_) => FILE_EXISTS_MESSAGE,
+    _ => ERR_MESSAGE
   };
 }
\ No newline at end of file
@@ -1,10 +1,8 @@
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
-use std::net::TcpStream;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::net::{TcpStream, TcpListener};
 
-use crossbeam_utils::thread as cross_thread;
-use crossbeam_channel::{unbounded, Sender, Receiver};
+use crossbeam_channel::{bounded, Sender, Receiver};
 
 pub mod headers;
 pub mod io;
@@ -12,16 +10,38 @@ pub mod errors;
 pub mod process;
 pub mod server;
 pub mod assembler;
+pub mod config;
+pub mod reaper;
+pub mod store;
+pub mod admission;
+pub mod handle;
+pub mod client;
+#[cfg(all(feature = "mio", unix))]
+pub mod event;
+#[cfg(feature = "tokio")]
+pub mod aio;
 
 use crate::process::Lease;
 use crate::headers::{Headers, HeaderType};
+use crate::config::{ServerConfig, Backend};
+use crate::store::{LeaseStore, MemoryStore};
+use crate::store::journal::JournalStore;
+use crate::admission::{Load, Limits};
+use crate::assembler::spool;
+use crate::errors::Errors;
+use crate::handle::{ServerHandle, ShutdownSignal, spawn_stage};
 
 #[derive(Debug)]
 pub struct Request {
     client: TcpStream,
     lease: Option<Lease>,
     headers: Option<Headers>,
-    chunk: Option<Vec<u8>>
+    chunk: Option<Vec<u8>>,
+    /// sent to the client following the OK message
+    response: Option<Vec<u8>>,
+    /// admitted by the server backend before it read the chunk
+    /// body, with the chunk bytes taken from the in flight budget
+    admitted: bool
 }
 
 impl Request {
@@ -33,26 +53,107 @@ impl Request {
 }
 
 pub struct Cache {
-    leases: Mutex<HashMap<String, Lease>>
+    leases: Mutex<HashMap<String, Lease>>,
+    store: Box<dyn LeaseStore>,
+    load: Load,
+    limits: Limits,
+    spools: spool::Locks
+}
+
+impl Cache {
+    pub fn new(store: Box<dyn LeaseStore>, config: &ServerConfig) -> Cache {
+        return Cache {
+            leases: Mutex::new(HashMap::new()),
+            store,
+            load: Load::default(),
+            limits: Limits::new(config),
+            spools: spool::Locks::default()
+        };
+    }
 }
 
 pub fn start_server(url: String) {
-    let (process_s, process_r): (Sender<Request>, Receiver<Request>) = unbounded();
-    let (assembler_s, assembler_r): (Sender<Request>, Receiver<Request>) = unbounded();
-    let cache = Arc::new(Cache {
-        leases: Mutex::new(HashMap::new()),
+    let config = ServerConfig::builder()
+        .bind_address(url)
+        .build()
+        .expect("Invalid default config");
+    start_server_with_config(config);
+}
+
+/// Blocks for as long as the server runs.
+pub fn start_server_with_config(config: ServerConfig) {
+    spawn_server(config).expect("Failed to start server").wait();
+}
+
+/// Starts the server on its own threads and returns
+/// a handle for shutting it down.
+pub fn spawn_server(config: ServerConfig) -> Result<ServerHandle, Errors> {
+    let config = Arc::new(config);
+    let (done_s, done_r): (Sender<()>, Receiver<()>) = bounded(0);
+    let (shutdown_s, shutdown) = handle::signal();
+
+    let listener = match TcpListener::bind(&config.bind_address) {
+        Ok(l) => l,
+        Err(e) => return Err(Errors::UnexpectedError(format!("Failed to bind: {}", e)))
+    };
+    let local_addr = match listener.local_addr() {
+        Ok(a) => a,
+        Err(e) => return Err(Errors::UnexpectedError(format!("Failed to get local address: {}", e)))
+    };
+    let (cache, process_s) = start_stages(&config, &done_s, shutdown.clone())?;
+
+    // stages stop in order as the queue feeding them closes,
+    // starting from the server once it sees the shutdown
+    let s_cache = cache.clone();
+    let s_config = config.clone();
+    spawn_stage(&done_s, move || match s_config.backend {
+        Backend::Threaded => server::start(listener, s_config, s_cache, process_s, shutdown),
+        #[cfg(all(feature = "mio", unix))]
+        Backend::EventLoop => event::start(listener, s_config, s_cache, process_s, shutdown),
     });
 
-    cross_thread::scope(|scope| {
-        let s_cache = cache.clone();
-        scope.spawn(move |_| server::start(url, s_cache, process_s));
+    return Ok(ServerHandle::new(local_addr, cache, shutdown_s, done_r));
+}
+
+/// Checks the config, creates the storage directories,
+/// reconciles the leases left from the last run and starts
+/// every stage past the server backend, which feeds them
+/// through the returned sender. Shared by every backend, so
+/// they start and stop the same way.
+fn start_stages(config: &Arc<ServerConfig>, done_s: &Sender<()>, shutdown: ShutdownSignal) -> Result<(Arc<Cache>, Sender<Request>), Errors> {
+    config.check()?;
+    let (process_s, process_r): (Sender<Request>, Receiver<Request>) = bounded(config.queue_depth);
+    let (assembler_s, assembler_r): (Sender<Request>, Receiver<Request>) = bounded(config.queue_depth);
+
+    config.create_dirs()?;
+    let cache = open_cache(config)?;
+
+    let p_cache = cache.clone();
+    let p_config = config.clone();
+    spawn_stage(done_s, move || process::start(p_config, p_cache, process_r, assembler_s));
+
+    let a_cache = cache.clone();
+    let a_config = config.clone();
+    spawn_stage(done_s, move || assembler::start(a_config, a_cache, assembler_r));
+
+    let r_cache = cache.clone();
+    let r_config = config.clone();
+    spawn_stage(done_s, move || reaper::start(r_cache, r_config, shutdown));
+
+    return Ok((cache, process_s));
+}
 
-        let p_cache = cache.clone();
-        scope.spawn(move |_| process::start(p_cache, process_r, assembler_s));
+/// Cache backed by the configured lease store, with
+/// leases left over from the last run reconciled.
+fn open_cache(config: &ServerConfig) -> Result<Arc<Cache>, Errors> {
+    let store: Box<dyn LeaseStore> = match config.lease_journal_path() {
+        Some(location) => Box::new(JournalStore::open(&location)?),
+        None => Box::new(MemoryStore)
+    };
+    let cache = Arc::new(Cache::new(store, config));
+    store::reconcile(&cache, config)?;
 
-        let a_cache = cache.clone();
-        scope.spawn(move |_| assembler::start(a_cache, assembler_r));
-    }).expect("Failed to create scope");
+    return Ok(cache);
 }
 
 #[cfg(test)]
@@ -1,15 +1,19 @@
-use std::sync::Arc;
-use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::collections::{HashMap, HashSet};
 use std::net::{TcpStream, Shutdown};
-use std::time::{SystemTime, UNIX_EPOCH};
-use crossbeam_channel::{Sender, Receiver};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crossbeam_channel::{bounded, Sender, Receiver};
 use uuid::Uuid;
+use log::warn;
 
-use crate::{Request, Cache};
+use crate::{Request, Cache, admission};
 use crate::headers::read::read_headers;
 use crate::headers::HeaderType;
 use crate::errors::Errors;
 use crate::io;
+use crate::assembler::checksum::Algorithm;
+use crate::config::ServerConfig;
 
 #[derive(Debug, Clone)]
 pub struct Lease {
@@ -21,59 +25,157 @@ pub struct Lease {
   pub chunks_sent: u32,
   pub chunk_nums: HashSet<u32>,
   pub ns_last_sent: u128,
-  pub in_use: bool
+  /// chunk_num to chunk_length of the chunks being read and
+  /// spooled. Up to max_lease_writers at once.
+  pub writers: HashMap<u32, u32>,
+  /// the file is complete and the finalizer has it
+  pub finalizing: bool,
+  /// chunk_num of the chunk shorter than min_chunk_bytes.
+  /// Only the last chunk of the file may be that short.
+  pub last_chunk_num: Option<u32>
 }
 
-pub fn start(cache: Arc<Cache>, process_r: Receiver<Request>, assembler_s: Sender<Request>) {
+impl Lease {
+  /// Whether a chunk is being written or the file finalized.
+  /// A lease in use can't be cancelled or expired.
+  pub fn in_use(&self) -> bool {
+    return self.finalizing || !self.writers.is_empty();
+  }
+
+  /// bytes_left less those the writers will take up.
+  pub fn bytes_unclaimed(&self) -> u32 {
+    let claimed: u32 = self.writers.values().sum();
+    return self.bytes_left.saturating_sub(claimed);
+  }
+
+  /// The short chunk of the file, written or being written.
+  fn short_chunk_num(&self, min_chunk_bytes: u32) -> Option<u32> {
+    return self.last_chunk_num.or_else(|| {
+      return self.writers.iter()
+        .find(|(_, length)| **length < min_chunk_bytes)
+        .map(|(num, _)| *num);
+    });
+  }
+}
+
+pub fn start(config: Arc<ServerConfig>, cache: Arc<Cache>, process_r: Receiver<Request>, assembler_s: Sender<Request>) {
+  let (worker_s, workers) = start_workers(config, cache, assembler_s);
+
+  while let Ok(request) = process_r.recv() {
+    worker_s.send(request).expect("Unhandled sent to worker thread");
+  }
+
+  // server stopped, let the workers drain the queue
+  drop(worker_s);
+  for worker in workers {
+    worker.join().ok();
+  }
+}
+
+/// Reading headers and chunk bodies blocks on the client, so
+/// requests are spread over a pool of workers. A lease takes
+/// up to max_lease_writers chunks at a time.
+fn start_workers(config: Arc<ServerConfig>, cache: Arc<Cache>, assembler_s: Sender<Request>) -> (Sender<Request>, Vec<JoinHandle<()>>) {
+  let (worker_s, worker_r): (Sender<Request>, Receiver<Request>) = bounded(config.queue_depth);
+  let worker_r = Arc::new(Mutex::new(worker_r));
+  let mut workers = Vec::with_capacity(config.process_workers);
+
+  for _ in 0..config.process_workers {
+    let cfg = config.clone();
+    let c = cache.clone();
+    let r = worker_r.clone();
+    let a = assembler_s.clone();
+    workers.push(thread::spawn(move || process(cfg, c, r, a)));
+  }
+
+  return (worker_s, workers);
+}
+
+fn process(config: Arc<ServerConfig>, cache: Arc<Cache>, worker_r: Arc<Mutex<Receiver<Request>>>, assembler_s: Sender<Request>) {
   loop {
-    if let Ok(mut request) = process_r.recv() {
+    let receiver = worker_r.lock().expect("Unhandled lock on worker receiver");
+    let mut request = match receiver.recv() {
+      Ok(r) => r,
+      Err(_) => return
+    };
+    drop(receiver);
 
-      match get_request_headers(&mut request) {
-        Ok(()) => (),
-        Err(e) => {
-          println!("{:?}", e);
-          io::write::write_err(&mut request.client).ok();
-          request.client.shutdown(Shutdown::Both).ok();
-          continue;
-        }
+    match get_request_headers(&mut request) {
+      Ok(()) => (),
+      Err(e) => {
+        warn!("{:?}", e);
+        io::write::write_error(&mut request.client, &e).ok();
+        request.client.shutdown(Shutdown::Both).ok();
+        continue;
       }
+    }
+
+    // hold off reading the chunk body until the assembler has
+    // caught up enough, unless the backend held off for us
+    let in_flight = in_flight_bytes(&request);
+    if in_flight > 0 && !request.admitted && !cache.load.acquire(in_flight, cache.limits.max_in_flight_bytes(), config.read_timeout) {
+      let e = Errors::BusyError(admission::RetryHint::new(&cache));
+      io::write::write_error(&mut request.client, &e).ok();
+      request.client.shutdown(Shutdown::Both).ok();
+      continue;
+    }
 
-      let result = match request.headers.as_ref().unwrap().header_type {
-        HeaderType::LEASE => handle_lease_request(&mut request, &cache),
-        HeaderType::CHUNK => handle_chunk_request(&mut request, &cache),
-        HeaderType::CANCEL => handle_cancel_request(&mut request, &cache),
-        HeaderType::FINAL => {
-          // not possible...
-          Err(Errors::UnexpectedError("this is impossible...".to_string()))
-        },
-        HeaderType::ERROR => {
-          // dunno what you are...
-          // shouldn't happen. But kill that client!
-          Err(Errors::InvalidRequest("Header Type is Error".to_string()))
-        },
-      };
-
-      match result {
-        Ok(send_to_assembler) => {
-          if send_to_assembler {
-            assembler_s.send(request).expect("Unhandled assembler channel error");
+    let result = match request.headers.as_ref().unwrap().header_type {
+      HeaderType::LEASE => handle_lease_request(&mut request, &config, &cache),
+      HeaderType::CHUNK => handle_chunk_request(&mut request, &config, &cache),
+      HeaderType::CANCEL => handle_cancel_request(&mut request, &cache),
+      HeaderType::STATUS => handle_status_request(&mut request, &cache),
+      HeaderType::FINAL => {
+        // not possible...
+        Err(Errors::UnexpectedError("this is impossible...".to_string()))
+      },
+      HeaderType::ERROR => {
+        // dunno what you are...
+        // shouldn't happen. But kill that client!
+        Err(Errors::InvalidRequest("Header Type is Error".to_string()))
+      },
+    };
+
+    match result {
+      Ok(send_to_assembler) => {
+        if send_to_assembler {
+          // the assembler releases the in flight bytes
+          if let Some(chunk) = request.chunk.as_ref() {
+            cache.load.reserve(chunk.len());
           }
-        },
-        Err(e) => {
-          println!("{:?}", e);
-          io::write::write_err(&mut request.client).ok();
-          request.client.shutdown(Shutdown::Both).ok();
+          assembler_s.send(request).expect("Unhandled assembler channel error");
+        } else {
+          cache.load.free(in_flight);
         }
-      };
-    }
+      },
+      Err(e) => {
+        cache.load.free(in_flight);
+        warn!("{:?}", e);
+        io::write::write_error(&mut request.client, &e).ok();
+        request.client.shutdown(Shutdown::Both).ok();
+      }
+    };
   }
 }
 
+/// Reads the headers off of the client, unless the server
+/// backend already did, and works out the request type.
 fn get_request_headers(request: &mut Request) -> Result<(), Errors> {
-  let mut headers = read_headers(&mut request.client)?;
+  let mut headers = match request.headers.take() {
+    Some(h) => h,
+    None => match read_headers(&mut request.client) {
+      Ok(h) => h,
+      Err(Errors::ReadRetryError) => {
+        return Err(Errors::TimeoutError("No headers within the read timeout".to_string()));
+      },
+      Err(e) => return Err(e)
+    }
+  };
 
   if headers.is_cancel_type() {
     headers.set_header_type(HeaderType::CANCEL);
+  } else if headers.is_status_type() {
+    headers.set_header_type(HeaderType::STATUS);
   } else if headers.is_lease_type() {
     headers.set_header_type(HeaderType::LEASE);
   } else if headers.is_chunk_type() {
@@ -85,7 +187,17 @@ fn get_request_headers(request: &mut Request) -> Result<(), Errors> {
   return Ok(());
 }
 
-fn handle_lease_request(request: &mut Request, cache: &Arc<Cache>) -> Result<bool, Errors> {
+/// Chunk bytes the request will read into memory.
+fn in_flight_bytes(request: &Request) -> u64 {
+  let headers = request.headers.as_ref().unwrap();
+
+  return match headers.header_type {
+    HeaderType::LEASE | HeaderType::CHUNK => headers.chunk_length.unwrap_or(0) as u64,
+    _ => 0
+  };
+}
+
+fn handle_lease_request(request: &mut Request, config: &Arc<ServerConfig>, cache: &Arc<Cache>) -> Result<bool, Errors> {
   let lease_id = Uuid::new_v4().to_string();
   let headers = request.headers.as_ref().unwrap();
   let checksum = headers.checksum.as_ref().unwrap();
@@ -94,27 +206,40 @@ fn handle_lease_request(request: &mut Request, cache: &Arc<Cache>) -> Result<boo
   let chunk_length = headers.chunk_length.as_ref().unwrap();
   let chunk_num = headers.chunk_num.as_ref().unwrap();
 
+  if Algorithm::from_hash(checksum).is_none() {
+    return Err(Errors::InvalidRequest("Unsupported checksum algorithm".to_string()));
+  }
+
+  check_file_name(file_name)?;
+
+  admission::admit(cache, *chunk_length, true)?;
+
   let lease = Lease {
     id: lease_id.to_string(),
     hash: checksum.to_string(),
     file_name: file_name.to_string(),
     file_length: *file_length,
     chunk_nums: HashSet::new(),
-    bytes_left: 0,
+    bytes_left: *file_length,
     chunks_sent: 0,
     ns_last_sent: 0,
-    in_use: true
+    writers: HashMap::new(),
+    finalizing: false,
+    last_chunk_num: None
   };
 
+  check_chunk_range(&lease, chunk_num, chunk_length, config)?;
+
   request.lease = Some(lease);
-  request.chunk = Some(read_retry_chunk(&mut request.client, chunk_length)?);
+  if let Some(lease) = request.lease.as_mut() {
+    lease.writers.insert(*chunk_num, *chunk_length);
+  }
+  request.chunk = Some(read_retry_chunk(&mut request.client, request.chunk.take(), config, chunk_length, &headers.chunk_checksum)?);
   if let Some(lease) = request.lease.as_mut() {
     lease.ns_last_sent = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards. lol")
         .as_nanos();
-
-    lease.chunk_nums.insert(*chunk_num);
   }
 
   if let Ok(mut leases) = cache.leases.lock() {
@@ -126,31 +251,48 @@ fn handle_lease_request(request: &mut Request, cache: &Arc<Cache>) -> Result<boo
   return Ok(true);
 }
 
-fn handle_chunk_request(request: &mut Request, cache: &Arc<Cache>) -> Result<bool, Errors> {
+fn handle_chunk_request(request: &mut Request, config: &Arc<ServerConfig>, cache: &Arc<Cache>) -> Result<bool, Errors> {
   let headers = request.headers.as_ref().unwrap();
   let lease_id = headers.lease_id.as_ref().unwrap();
   let chunk_length = headers.chunk_length.as_ref().unwrap();
   let chunk_num = headers.chunk_num.as_ref().unwrap();
+  let mut duplicate = false;
+
+  admission::admit(cache, *chunk_length, false)?;
 
   if let Ok(mut leases) = cache.leases.lock() {
-    request.lease = match leases.get(lease_id) {
+    request.lease = match leases.get_mut(lease_id) {
       Some(l) => {
-        if l.in_use {
+        // a chunk_num being written is answered once it's done,
+        // as written or free to resend
+        let busy = l.finalizing
+          || l.writers.len() >= cache.limits.max_lease_writers() as usize
+          || l.writers.contains_key(chunk_num);
+        if busy {
           io::write::write_lease_in_use(&mut request.client)?;
           request.client.shutdown(Shutdown::Both).ok();
           return Ok(false);
         }
-        let mut lc = l.clone();
-        lc.in_use = true;
-        Some(lc)
+        if l.chunk_nums.contains(chunk_num) {
+          duplicate = true;
+          None
+        } else {
+          check_chunk_range(l, chunk_num, chunk_length, config)?;
+          l.writers.insert(*chunk_num, *chunk_length);
+          Some(l.clone())
+        }
       },
       None => None
     };
+  }
 
-    if let Some(lease) = &request.lease {
-      let lc = lease.clone();
-      leases.insert(lease_id.to_string(), lc);
-    }
+  if duplicate {
+    // already have this chunk. Read past it so the client
+    // gets the acknowledgement, but don't write it again.
+    read_retry_chunk(&mut request.client, request.chunk.take(), config, chunk_length, &headers.chunk_checksum)?;
+    io::write::write_duplicate_chunk(&mut request.client)?;
+    request.client.shutdown(Shutdown::Both).ok();
+    return Ok(false);
   }
 
   if request.lease.is_none() {
@@ -161,17 +303,19 @@ fn handle_chunk_request(request: &mut Request, cache: &Arc<Cache>) -> Result<boo
     return Ok(false);
   }
 
-  request.chunk = Some(read_retry_chunk(&mut request.client, chunk_length)?);
+  request.chunk = match read_retry_chunk(&mut request.client, request.chunk.take(), config, chunk_length, &headers.chunk_checksum) {
+    Ok(chunk) => Some(chunk),
+    Err(e) => {
+      // give the lease back so the client can resend the chunk
+      release_lease(cache, lease_id, chunk_num);
+      return Err(e);
+    }
+  };
   if let Some(lease) = request.lease.as_mut() {
     lease.ns_last_sent = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards. lol")
         .as_nanos();
-
-    lease.chunk_nums.insert(*chunk_num);
-  }
-  if headers.is_final_type(&request.lease.as_ref().unwrap().bytes_left) {
-    request.set_header_type(HeaderType::FINAL);
   }
 
   return Ok(true);
@@ -183,7 +327,7 @@ fn handle_cancel_request(request: &mut Request, cache: &Arc<Cache>) -> Result<bo
   let mut leases = cache.leases.lock().expect("Unhandled cache lease lock");
   request.lease = match leases.get(lease_id) {
     Some(lease) => {
-      if lease.in_use {
+      if lease.in_use() {
         io::write::write_lease_in_use(&mut request.client)?;
         request.client.shutdown(Shutdown::Both).ok();
         return Ok(false);
@@ -196,9 +340,10 @@ fn handle_cancel_request(request: &mut Request, cache: &Arc<Cache>) -> Result<bo
 
   if request.lease.is_none() {
     // bad client... or something
-    // lease is already removed. 
-    // Don't worry bout it. Or worry bout it?
+    // lease is already removed, so
+    // nothing is left to discard.
     io::write::write_ok(&mut request.client)?;
+    io::write::write_bytes(&mut request.client, &0u64.to_le_bytes())?;
     request.client.shutdown(Shutdown::Both).ok();
     return Ok(false);
   }
@@ -206,34 +351,458 @@ fn handle_cancel_request(request: &mut Request, cache: &Arc<Cache>) -> Result<bo
   return Ok(true);
 }
 
-fn read_retry_chunk(client: &mut TcpStream, chunk_length: &u32) -> Result<Vec<u8>, Errors> {
+/// Responds with what the lease already holds so a client can
+/// resume the upload: bytes_left, chunks_sent, then the count of
+/// received chunk_num ranges followed by each [start][end] pair.
+fn handle_status_request(request: &mut Request, cache: &Arc<Cache>) -> Result<bool, Errors> {
+  let lease_id = request.headers.as_ref().unwrap().lease_id.as_ref().unwrap();
+
+  let lease = match cache.leases.lock() {
+    Ok(leases) => leases.get(lease_id).cloned(),
+    Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()))
+  };
+
+  let lease = match lease {
+    Some(l) => l,
+    None => {
+      io::write::write_no_lease(&mut request.client)?;
+      request.client.shutdown(Shutdown::Both).ok();
+      return Ok(false);
+    }
+  };
+
+  let ranges = chunk_ranges(&lease.chunk_nums);
+  let mut status: Vec<u8> = Vec::with_capacity(4 + 4 + 4 + ranges.len() * 8);
+  status.extend_from_slice(&lease.bytes_left.to_le_bytes());
+  status.extend_from_slice(&lease.chunks_sent.to_le_bytes());
+  status.extend_from_slice(&(ranges.len() as u32).to_le_bytes());
+  for (start, end) in ranges {
+    status.extend_from_slice(&start.to_le_bytes());
+    status.extend_from_slice(&end.to_le_bytes());
+  }
+
+  io::write::write_ok(&mut request.client)?;
+  io::write::write_bytes(&mut request.client, &status)?;
+  request.client.shutdown(Shutdown::Both).ok();
+
+  return Ok(false);
+}
+
+/// Collapses the chunk_nums into sorted, inclusive ranges.
+pub fn chunk_ranges(chunk_nums: &HashSet<u32>) -> Vec<(u32, u32)> {
+  let mut nums: Vec<u32> = chunk_nums.iter().copied().collect();
+  nums.sort_unstable();
+
+  let mut ranges: Vec<(u32, u32)> = Vec::new();
+  for num in nums {
+    match ranges.last_mut() {
+      Some((_, end)) if *end + 1 == num => *end = num,
+      _ => ranges.push((num, num))
+    }
+  }
+
+  return ranges;
+}
+
+/// Errors if the chunk can't be part of the lease's file. Every
+/// chunk but the last is at least min_chunk_bytes, which bounds
+/// how high the chunk_num can go. Chunks still being written
+/// count as sent.
+fn check_chunk_range(lease: &Lease, chunk_num: &u32, chunk_length: &u32, config: &ServerConfig) -> Result<(), Errors> {
+  if *chunk_length > config.max_chunk_bytes {
+    return Err(Errors::InvalidRequest("Chunk is longer than the maximum".to_string()));
+  }
+
+  let bytes_left = lease.bytes_unclaimed();
+  if *chunk_length > bytes_left {
+    return Err(Errors::OutOfRangeError("Chunk length exceeds bytes left in the file".to_string()));
+  }
+
+  let last_chunk_num = lease.short_chunk_num(config.min_chunk_bytes);
+  let max_chunk_num = match last_chunk_num {
+    Some(last) => last,
+    None => lease.file_length.saturating_sub(1) / config.min_chunk_bytes
+  };
+  if *chunk_num > max_chunk_num {
+    return Err(Errors::OutOfRangeError("Chunk number exceeds the file length".to_string()));
+  }
+
+  // a short chunk that completes the file is always fine.
+  // Otherwise it has to be the last chunk of the file.
+  let short = *chunk_length < config.min_chunk_bytes && *chunk_length != bytes_left;
+  let later = lease.chunk_nums.iter().chain(lease.writers.keys()).any(|n| n > chunk_num);
+  if short && (last_chunk_num.is_some() || later) {
+    return Err(Errors::InvalidRequest("Only the last chunk can be shorter than the minimum".to_string()));
+  }
+
+  return Ok(());
+}
+
+/// File names become paths in the storage directories, so they
+/// can't climb out of them. Hidden names are left for the server.
+fn check_file_name(file_name: &str) -> Result<(), Errors> {
+  let invalid = file_name.is_empty()
+    || file_name.starts_with('.')
+    || file_name.contains(['/', '\\', '\0']);
+
+  if invalid {
+    return Err(Errors::InvalidRequest("Invalid file name".to_string()));
+  }
+
+  return Ok(());
+}
+
+fn release_lease(cache: &Arc<Cache>, lease_id: &str, chunk_num: &u32) {
+  if let Ok(mut leases) = cache.leases.lock() {
+    if let Some(lease) = leases.get_mut(lease_id) {
+      lease.writers.remove(chunk_num);
+    }
+  }
+}
+
+/// Reads in the chunk body, retrying while nothing has arrived
+/// yet. The body has to arrive at the configured minimum
+/// throughput. A body the server backend already buffered is
+/// used as is. If the headers carried a chunk checksum, the body
+/// must match it.
+fn read_retry_chunk(client: &mut TcpStream, buffered: Option<Vec<u8>>, config: &Arc<ServerConfig>, chunk_length: &u32, chunk_checksum: &Option<u32>) -> Result<Vec<u8>, Errors> {
   let mut chunk: Option<Vec<u8>> = None;
   let mut retries = 0;
+  let deadline = config.chunk_deadline(*chunk_length);
 
-  while retries < 3 {
-    match io::read::pluck_stream(client, chunk_length) {
-      Ok(c) => {
-        if c.len() != *chunk_length as usize {
-          return Err(Errors::ReadLengthError("Failed to read entire chunk length".to_string()));
-        }
+  if let Some(buffered) = buffered {
+    if buffered.len() != *chunk_length as usize {
+      return Err(Errors::ReadLengthError("Buffered chunk doesn't match the chunk length".to_string()));
+    }
+    chunk = Some(buffered);
+  }
 
+  while chunk.is_none() && retries < config.chunk_retries {
+    // retries wait no longer than what's left before the deadline
+    if let Some(deadline) = deadline {
+      let left = deadline.saturating_duration_since(Instant::now());
+      if left.is_zero() {
+        break;
+      }
+      if client.set_read_timeout(Some(left.min(config.read_timeout))).is_err() {
+        return Err(Errors::UnexpectedError("Failed to set the read timeout".to_string()));
+      }
+    }
+
+    match io::read::read_exact_within(client, *chunk_length as usize, deadline) {
+      Ok(c) => {
         chunk = Some(c);
         break;
       },
-      Err(e) => {
-        match e {
-          Errors::ReadRetryError => {
-            retries += 1;
-          },
-          _ => break
-        }
+      Err(Errors::ReadRetryError) => {
+        retries += 1;
+      },
+      Err(e) => return Err(e)
+    };
+  }
+
+  if let Some(chunk) = chunk {
+    if let Some(expected) = chunk_checksum {
+      if crc32c::crc32c(&chunk) != *expected {
+        return Err(Errors::ChunkChecksumError("Chunk does not match chunk checksum".to_string()));
       }
+    }
+
+    return Ok(chunk);
+  }
+
+  return Err(Errors::TimeoutError("No chunk within the read timeout".to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{Read, Write};
+  use std::net::TcpListener;
+  use std::time::Duration;
+  use crate::headers::{Headers, MIN_CHUNK_BYTES};
+  use crate::store::MemoryStore;
+
+  /// A cache holding one idle lease of three chunks.
+  fn cache_with_lease(config: &ServerConfig, lease_id: &str) -> Arc<Cache> {
+    let cache = Arc::new(Cache::new(Box::new(MemoryStore), config));
+    cache.leases.lock().unwrap().insert(lease_id.to_string(), Lease {
+      id: lease_id.to_string(),
+      file_name: "process_test".to_string(),
+      hash: String::new(),
+      file_length: MIN_CHUNK_BYTES * 3,
+      bytes_left: MIN_CHUNK_BYTES * 3,
+      chunks_sent: 0,
+      chunk_nums: HashSet::new(),
+      ns_last_sent: 0,
+      writers: HashMap::new(),
+      finalizing: false,
+      last_chunk_num: None
+    });
+    return cache;
+  }
+
+  /// The server end of a loopback connection as a request
+  /// with its headers read, and the client end.
+  fn chunk_request(lease_id: &str, chunk_num: u32, chunk: &[u8], chunk_checksum: u32) -> (Request, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    let headers = Headers {
+      header_type: HeaderType::ERROR,
+      lease_id: Some(lease_id.to_string()),
+      checksum: None,
+      file_name: None,
+      file_length: None,
+      chunk_length: Some(chunk.len() as u32),
+      chunk_num: Some(chunk_num),
+      chunk_checksum: Some(chunk_checksum),
+      cancel: None
     };
+    let request = Request { client: server, lease: None, headers: Some(headers), chunk: None, response: None, admitted: false };
+
+    return (request, client);
   }
 
-  if chunk.is_some() {
-    return Ok(chunk.unwrap());
+  #[test]
+  fn collapses_chunk_nums_into_ranges() {
+    let nums: HashSet<u32> = [7, 0, 2, 1, 4, 5, 9].iter().copied().collect();
+    assert_eq!(chunk_ranges(&nums), vec![(0, 2), (4, 5), (7, 7), (9, 9)]);
+    assert_eq!(chunk_ranges(&HashSet::new()), vec![]);
   }
 
-  return Err(Errors::ReadError("Failed to read in chunk".to_string()));
-}
\ No newline at end of file
+  #[test]
+  fn rejects_chunks_past_the_file_length() {
+    let config = ServerConfig::default();
+    let lease = Lease {
+      id: "range".to_string(),
+      file_name: "range".to_string(),
+      hash: String::new(),
+      file_length: MIN_CHUNK_BYTES * 3,
+      bytes_left: MIN_CHUNK_BYTES * 2,
+      chunks_sent: 1,
+      chunk_nums: HashSet::new(),
+      ns_last_sent: 0,
+      writers: HashMap::new(),
+      finalizing: false,
+      last_chunk_num: None
+    };
+
+    assert!(check_chunk_range(&lease, &2, &MIN_CHUNK_BYTES, &config).is_ok());
+    assert!(check_chunk_range(&lease, &1, &(MIN_CHUNK_BYTES * 2), &config).is_ok());
+    assert!(check_chunk_range(&lease, &3, &MIN_CHUNK_BYTES, &config).is_err());
+    assert!(check_chunk_range(&lease, &1, &(MIN_CHUNK_BYTES * 2 + 1), &config).is_err());
+  }
+
+  #[test]
+  fn only_allows_a_short_last_chunk() {
+    let config = ServerConfig::default();
+    let mut lease = Lease {
+      id: "short".to_string(),
+      file_name: "short".to_string(),
+      hash: String::new(),
+      file_length: MIN_CHUNK_BYTES * 3 + 10,
+      bytes_left: MIN_CHUNK_BYTES * 2 + 10,
+      chunks_sent: 1,
+      chunk_nums: [1].iter().copied().collect(),
+      ns_last_sent: 0,
+      writers: HashMap::new(),
+      finalizing: false,
+      last_chunk_num: None
+    };
+
+    assert!(check_chunk_range(&lease, &3, &10, &config).is_ok());
+    assert!(check_chunk_range(&lease, &0, &10, &config).is_err());
+
+    lease.last_chunk_num = Some(3);
+    lease.chunk_nums.insert(3);
+    lease.bytes_left -= 10;
+    assert!(check_chunk_range(&lease, &4, &MIN_CHUNK_BYTES, &config).is_err());
+    assert!(check_chunk_range(&lease, &2, &10, &config).is_err());
+    assert!(check_chunk_range(&lease, &2, &MIN_CHUNK_BYTES, &config).is_ok());
+  }
+
+  #[test]
+  fn counts_chunks_being_written_as_sent() {
+    let config = ServerConfig::default();
+    let mut lease = Lease {
+      id: "writers".to_string(),
+      file_name: "writers".to_string(),
+      hash: String::new(),
+      file_length: MIN_CHUNK_BYTES * 3 + 10,
+      bytes_left: MIN_CHUNK_BYTES * 3 + 10,
+      chunks_sent: 0,
+      chunk_nums: HashSet::new(),
+      ns_last_sent: 0,
+      writers: [(0, MIN_CHUNK_BYTES), (2, MIN_CHUNK_BYTES)].iter().copied().collect(),
+      finalizing: false,
+      last_chunk_num: None
+    };
+
+    assert!(lease.in_use());
+    assert_eq!(lease.bytes_unclaimed(), MIN_CHUNK_BYTES + 10);
+    assert!(check_chunk_range(&lease, &1, &(MIN_CHUNK_BYTES * 2), &config).is_err());
+    assert!(check_chunk_range(&lease, &1, &10, &config).is_err());
+    assert!(check_chunk_range(&lease, &3, &10, &config).is_ok());
+
+    // a short chunk being written is the last of the file
+    lease.writers.insert(3, 10);
+    assert!(check_chunk_range(&lease, &4, &MIN_CHUNK_BYTES, &config).is_err());
+    assert!(check_chunk_range(&lease, &1, &MIN_CHUNK_BYTES, &config).is_ok());
+  }
+
+  #[test]
+  fn retries_chunks_that_dont_match_their_checksum() {
+    let config = Arc::new(ServerConfig { process_workers: 1, ..ServerConfig::default() });
+    let cache = cache_with_lease(&config, "crc");
+    let (assembler_s, assembler_r) = bounded(1);
+    let (worker_s, workers) = start_workers(config, cache.clone(), assembler_s);
+
+    let chunk = vec![7u8; MIN_CHUNK_BYTES as usize];
+    let (request, mut client) = chunk_request("crc", 1, &chunk, crc32c::crc32c(&chunk) ^ 1);
+    worker_s.send(request).unwrap();
+    client.write_all(&chunk).unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(response[0], io::write::RETRY_MESSAGE[0]);
+    assert_eq!(response.len(), 1 + admission::RETRY_HINT_BYTES);
+
+    // the writer slot and budget are given back, and nothing is recorded
+    let lease = cache.leases.lock().unwrap().get("crc").cloned().unwrap();
+    assert!(lease.writers.is_empty());
+    assert!(!lease.chunk_nums.contains(&1));
+    assert_eq!(cache.load.in_flight_bytes(), 0);
+    assert!(assembler_r.try_recv().is_err());
+
+    drop(worker_s);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn stops_retrying_a_stalled_chunk_at_the_deadline() {
+    let config = Arc::new(ServerConfig {
+      read_timeout: Duration::from_millis(200),
+      chunk_retries: 10,
+      min_throughput: u32::MAX,
+      ..ServerConfig::default()
+    });
+    let (mut request, _client) = chunk_request("stall", 0, &[0u8; 16], 0);
+    request.client.set_read_timeout(Some(config.read_timeout)).unwrap();
+
+    let started = Instant::now();
+    match read_retry_chunk(&mut request.client, None, &config, &16, &None) {
+      Err(Errors::TimeoutError(_)) => (),
+      r => panic!("expected timeout, got {:?}", r)
+    };
+    assert!(started.elapsed() < config.read_timeout * 3);
+  }
+
+  #[test]
+  fn forwards_chunks_that_match_their_checksum() {
+    let config = Arc::new(ServerConfig { process_workers: 1, ..ServerConfig::default() });
+    let cache = cache_with_lease(&config, "crc");
+    let (assembler_s, assembler_r) = bounded(1);
+    let (worker_s, workers) = start_workers(config, cache.clone(), assembler_s);
+
+    let chunk = vec![9u8; MIN_CHUNK_BYTES as usize];
+    let (request, mut client) = chunk_request("crc", 1, &chunk, crc32c::crc32c(&chunk));
+    worker_s.send(request).unwrap();
+    client.write_all(&chunk).unwrap();
+
+    let forwarded = assembler_r.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(forwarded.chunk.as_ref().unwrap(), &chunk);
+    assert!(cache.leases.lock().unwrap()["crc"].writers.contains_key(&1));
+    assert_eq!(cache.load.in_flight_bytes(), chunk.len() as u64);
+
+    drop(worker_s);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn answers_chunks_of_a_cancelled_lease_with_no_lease() {
+    let config = Arc::new(ServerConfig { process_workers: 1, ..ServerConfig::default() });
+    let cache = cache_with_lease(&config, "cancel");
+    let (assembler_s, assembler_r) = bounded(1);
+    let (worker_s, workers) = start_workers(config, cache.clone(), assembler_s);
+
+    let (mut request, _client) = chunk_request("cancel", 0, &[], 0);
+    if let Some(headers) = request.headers.as_mut() {
+      headers.chunk_length = None;
+      headers.chunk_num = None;
+      headers.chunk_checksum = None;
+      headers.cancel = Some(true);
+    }
+    worker_s.send(request).unwrap();
+
+    // taken out of the cache and handed on to discard the spool
+    let cancelled = assembler_r.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(cancelled.lease.as_ref().unwrap().id, "cancel");
+    assert!(!cache.leases.lock().unwrap().contains_key("cancel"));
+
+    let chunk = vec![3u8; MIN_CHUNK_BYTES as usize];
+    let (request, mut client) = chunk_request("cancel", 1, &chunk, crc32c::crc32c(&chunk));
+    worker_s.send(request).unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).unwrap();
+    assert_eq!(response, io::write::NO_LEASE_MESSAGE.to_vec());
+
+    drop(worker_s);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+    assert_eq!(cache.load.in_flight_bytes(), 0);
+  }
+
+  #[test]
+  fn workers_take_requests_at_once_and_exit_when_disconnected() {
+    let config = Arc::new(ServerConfig { process_workers: 3, ..ServerConfig::default() });
+    let cache = cache_with_lease(&config, "pool");
+    let (assembler_s, assembler_r) = bounded(3);
+    let (worker_s, workers) = start_workers(config, cache.clone(), assembler_s);
+
+    let chunk = vec![5u8; MIN_CHUNK_BYTES as usize];
+    let mut clients = Vec::new();
+    for chunk_num in 0..3 {
+      let (request, client) = chunk_request("pool", chunk_num, &chunk, crc32c::crc32c(&chunk));
+      worker_s.send(request).unwrap();
+      clients.push(client);
+    }
+
+    // no bodies are sent yet, so each chunk
+    // only gets a writer from a worker of its own
+    let started = std::time::Instant::now();
+    while cache.leases.lock().unwrap()["pool"].writers.len() < 3 {
+      assert!(started.elapsed() < Duration::from_secs(5), "Workers didn't take the requests at once");
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    for client in clients.iter_mut() {
+      client.write_all(&chunk).unwrap();
+    }
+    for _ in 0..3 {
+      assembler_r.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    drop(worker_s);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+  }
+
+  #[test]
+  fn rejects_file_names_outside_the_storage_dirs() {
+    assert!(check_file_name("photo.jpg").is_ok());
+    assert!(check_file_name("").is_err());
+    assert!(check_file_name("..").is_err());
+    assert!(check_file_name(".hidden").is_err());
+    assert!(check_file_name("../../etc/passwd").is_err());
+    assert!(check_file_name("dir\\photo.jpg").is_err());
+  }
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, error};
+
+use crate::Cache;
+use crate::config::ServerConfig;
+use crate::handle::ShutdownSignal;
+use crate::process::Lease;
+use crate::assembler::spool;
+
+pub fn start(cache: Arc<Cache>, config: Arc<ServerConfig>, shutdown: ShutdownSignal) {
+  while !shutdown.wait(config.reap_interval) {
+    for lease in reap(&cache, &config) {
+      info!("Expired lease {} for {}", lease.id, lease.file_name);
+    }
+  }
+}
+
+/// Removes leases that have been idle longer than the ttl and
+/// deletes their spool files. Leases with a chunk in flight are
+/// left alone.
+pub fn reap(cache: &Arc<Cache>, config: &ServerConfig) -> Vec<Lease> {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards. lol")
+    .as_nanos();
+
+  let expired: Vec<Lease> = match cache.leases.lock() {
+    Ok(mut leases) => {
+      let ids: Vec<String> = leases.values()
+        .filter(|l| !l.in_use() && now.saturating_sub(l.ns_last_sent) > config.lease_ttl.as_nanos())
+        .map(|l| l.id.to_string())
+        .collect();
+
+      ids.iter().filter_map(|id| leases.remove(id)).collect()
+    },
+    Err(_) => return Vec::new()
+  };
+
+  for lease in expired.iter() {
+    let result = cache.store.remove(&lease.id)
+      .and_then(|_| spool::discard(&spool::location(&config.incoming_path(), &lease.id, &lease.file_name)));
+    if let Err(e) = result {
+      error!("{:?}", e);
+    }
+  }
+
+  return expired;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+  use std::collections::{HashMap, HashSet};
+  use crate::store::MemoryStore;
+
+  fn lease(id: &str, ns_last_sent: u128, in_use: bool) -> Lease {
+    return Lease {
+      id: id.to_string(),
+      file_name: "reaper_test".to_string(),
+      hash: String::new(),
+      file_length: 0,
+      bytes_left: 0,
+      chunks_sent: 0,
+      chunk_nums: HashSet::new(),
+      ns_last_sent,
+      writers: HashMap::new(),
+      finalizing: in_use,
+      last_chunk_num: None
+    };
+  }
+
+  #[test]
+  fn reaps_only_idle_leases() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let config = ServerConfig {
+      lease_ttl: Duration::from_secs(60),
+      ..ServerConfig::default()
+    };
+    let cache = Arc::new(Cache::new(Box::new(MemoryStore), &config));
+    {
+      let mut leases = cache.leases.lock().unwrap();
+      leases.insert("idle".to_string(), lease("idle", 0, false));
+      leases.insert("busy".to_string(), lease("busy", 0, true));
+      leases.insert("fresh".to_string(), lease("fresh", now, false));
+    }
+
+    let expired = reap(&cache, &config);
+
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].id, "idle");
+    assert_eq!(cache.leases.lock().unwrap().len(), 2);
+  }
+}
@@ -1,35 +1,46 @@
 use std::net::TcpListener;
 use std::sync::Arc;
 use crossbeam_channel::Sender;
+use log::error;
 
-use crate::{Request, Cache};
-
-pub fn start(url: String, cache: Arc<Cache>, process_s: Sender<Request>) {
-  let listener = TcpListener::bind(url).unwrap();
+use crate::{Request, Cache, admission};
+use crate::config::ServerConfig;
+use crate::handle::ShutdownSignal;
 
+pub fn start(listener: TcpListener, config: Arc<ServerConfig>, cache: Arc<Cache>, process_s: Sender<Request>, shutdown: ShutdownSignal) {
   for stream in listener.incoming() {
+    if shutdown.is_set() {
+      break;
+    }
+
     match stream {
       Ok(client) => {
+        // a client that stalls can't hold up the process stage
+        let timeouts = client.set_read_timeout(Some(config.read_timeout))
+          .and_then(|_| client.set_write_timeout(Some(config.write_timeout)));
+        if let Err(err) = timeouts {
+          error!("{}", err);
+          continue;
+        }
 
         let request = Request {
           client,
           lease: None,
           headers: None,
-          chunk: None
+          chunk: None,
+          response: None,
+          admitted: false
         };
 
-        // TODO
-        //
-        // can server handle the load?
-        // decline client but send back stats
-        // about when to request back
-        // do I need to process the headers 
-        // first before I do this?
-        
-        process_s.send(request).unwrap();
+        // declined with a retry hint if the
+        // process stage is already backed up
+        if let Err(e) = admission::forward(request, &process_s, &cache) {
+          error!("{:?}", e);
+          break;
+        }
       },
       Err(err) => {
-        println!("{}", err);
+        error!("{}", err);
       }
     }
   }
@@ -0,0 +1,256 @@
+use std::io::{Read, Write, Cursor, BufReader, ErrorKind};
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::errors::Errors;
+use crate::process::Lease;
+use crate::store::LeaseStore;
+
+const SAVE_RECORD: u8 = 1;
+const REMOVE_RECORD: u8 = 2;
+
+/// Append-only file of [type][length][payload] records. A save
+/// record is written the first time a lease is seen and a remove
+/// record when it goes away. The journal is compacted on open.
+pub struct JournalStore {
+  location: PathBuf,
+  state: Mutex<JournalState>
+}
+
+struct JournalState {
+  file: File,
+  lease_ids: HashSet<String>
+}
+
+impl JournalStore {
+  pub fn open<P: AsRef<Path>>(location: P) -> Result<JournalStore, Errors> {
+    let location = location.as_ref().to_path_buf();
+    let leases = read_journal(&location)?;
+
+    // rewrite the journal with just the live leases
+    let compacted = location.with_extension("compact");
+    let result = File::create(&compacted)
+      .and_then(|mut file| {
+        for lease in leases.iter() {
+          file.write_all(&encode_save(lease))?;
+        }
+        file.sync_all()?;
+        return fs::rename(&compacted, &location);
+      });
+    if result.is_err() {
+      return Err(Errors::FileIOError("Failed to compact lease journal".to_string()));
+    }
+
+    let file = match OpenOptions::new().append(true).create(true).open(&location) {
+      Ok(f) => f,
+      Err(_) => return Err(Errors::FileIOError("Failed to open lease journal".to_string()))
+    };
+
+    return Ok(JournalStore {
+      location,
+      state: Mutex::new(JournalState {
+        file,
+        lease_ids: leases.iter().map(|l| l.id.to_string()).collect()
+      })
+    });
+  }
+
+  fn append(&self, state: &mut JournalState, record: &[u8]) -> Result<(), Errors> {
+    let result = state.file.write_all(record).and_then(|_| state.file.sync_data());
+    if result.is_err() {
+      return Err(Errors::FileIOError(format!("Failed to append to {:?}", self.location)));
+    }
+    return Ok(());
+  }
+}
+
+impl LeaseStore for JournalStore {
+  fn load(&self) -> Result<Vec<Lease>, Errors> {
+    return read_journal(&self.location);
+  }
+
+  fn save(&self, lease: &Lease) -> Result<(), Errors> {
+    let mut state = match self.state.lock() {
+      Ok(s) => s,
+      Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on lease journal".to_string()))
+    };
+
+    if state.lease_ids.contains(&lease.id) {
+      return Ok(());
+    }
+
+    self.append(&mut state, &encode_save(lease))?;
+    state.lease_ids.insert(lease.id.to_string());
+
+    return Ok(());
+  }
+
+  fn remove(&self, lease_id: &str) -> Result<(), Errors> {
+    let mut state = match self.state.lock() {
+      Ok(s) => s,
+      Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on lease journal".to_string()))
+    };
+
+    if !state.lease_ids.remove(lease_id) {
+      return Ok(());
+    }
+
+    let mut payload = Vec::new();
+    write_str(&mut payload, lease_id);
+    return self.append(&mut state, &encode_record(REMOVE_RECORD, &payload));
+  }
+
+  fn flush(&self) -> Result<(), Errors> {
+    let state = match self.state.lock() {
+      Ok(s) => s,
+      Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on lease journal".to_string()))
+    };
+
+    return match state.file.sync_all() {
+      Ok(()) => Ok(()),
+      Err(_) => Err(Errors::FileIOError(format!("Failed to sync {:?}", self.location)))
+    };
+  }
+}
+
+fn encode_record(record_type: u8, payload: &[u8]) -> Vec<u8> {
+  let mut record = Vec::with_capacity(payload.len() + 1 + 4);
+  record.push(record_type);
+  record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+  record.extend_from_slice(payload);
+  return record;
+}
+
+fn encode_save(lease: &Lease) -> Vec<u8> {
+  let mut payload = Vec::new();
+  write_str(&mut payload, &lease.id);
+  write_str(&mut payload, &lease.file_name);
+  write_str(&mut payload, &lease.hash);
+  payload.write_u32::<LittleEndian>(lease.file_length).ok();
+  return encode_record(SAVE_RECORD, &payload);
+}
+
+fn decode_save(payload: &[u8]) -> std::io::Result<Lease> {
+  let mut cursor = Cursor::new(payload);
+  let id = read_str(&mut cursor)?;
+  let file_name = read_str(&mut cursor)?;
+  let hash = read_str(&mut cursor)?;
+  let file_length = cursor.read_u32::<LittleEndian>()?;
+
+  return Ok(Lease {
+    id,
+    file_name,
+    hash,
+    file_length,
+    bytes_left: file_length,
+    chunks_sent: 0,
+    chunk_nums: HashSet::new(),
+    ns_last_sent: 0,
+    writers: HashMap::new(),
+    finalizing: false,
+    last_chunk_num: None
+  });
+}
+
+fn write_str(buffer: &mut Vec<u8>, value: &str) {
+  buffer.extend_from_slice(&(value.len() as u32).to_le_bytes());
+  buffer.extend_from_slice(value.as_bytes());
+}
+
+fn read_str(cursor: &mut Cursor<&[u8]>) -> std::io::Result<String> {
+  let length = cursor.read_u32::<LittleEndian>()? as usize;
+  let mut data = vec![0u8; length];
+  cursor.read_exact(&mut data)?;
+  return Ok(String::from_utf8_lossy(&data).to_string());
+}
+
+/// Replays the journal. A trailing record that was only
+/// partially written is ignored.
+fn read_journal(location: &Path) -> Result<Vec<Lease>, Errors> {
+  let file = match File::open(location) {
+    Ok(f) => f,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+    Err(_) => return Err(Errors::FileIOError("Failed to open lease journal".to_string()))
+  };
+
+  let mut reader = BufReader::new(file);
+  let mut leases: HashMap<String, Lease> = HashMap::new();
+  let mut order: Vec<String> = Vec::new();
+
+  loop {
+    let mut header = [0u8; 5];
+    if reader.read_exact(&mut header).is_err() {
+      break;
+    }
+
+    let length = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    let mut payload = vec![0u8; length];
+    if reader.read_exact(&mut payload).is_err() {
+      break;
+    }
+
+    match header[0] {
+      SAVE_RECORD => {
+        if let Ok(lease) = decode_save(&payload) {
+          order.push(lease.id.to_string());
+          leases.insert(lease.id.to_string(), lease);
+        }
+      },
+      REMOVE_RECORD => {
+        if let Ok(lease_id) = read_str(&mut Cursor::new(&payload[..])) {
+          leases.remove(&lease_id);
+        }
+      },
+      _ => return Err(Errors::ParseError("Unknown lease journal record".to_string()))
+    }
+  }
+
+  return Ok(order.iter().filter_map(|id| leases.remove(id)).collect());
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lease(id: &str) -> Lease {
+    return Lease {
+      id: id.to_string(),
+      file_name: "journal_test".to_string(),
+      hash: "sha256:abc".to_string(),
+      file_length: 2048,
+      bytes_left: 1024,
+      chunks_sent: 1,
+      chunk_nums: HashSet::new(),
+      ns_last_sent: 0,
+      writers: [(1, 1024)].iter().copied().collect(),
+      finalizing: false,
+      last_chunk_num: None
+    };
+  }
+
+  #[test]
+  fn replays_saves_and_removes() {
+    let location = std::env::temp_dir().join("rjchunker_journal_test");
+    fs::remove_file(&location).ok();
+
+    let store = JournalStore::open(&location).unwrap();
+    store.save(&lease("a")).unwrap();
+    store.save(&lease("b")).unwrap();
+    store.save(&lease("a")).unwrap();
+    store.remove("b").unwrap();
+    drop(store);
+
+    let store = JournalStore::open(&location).unwrap();
+    let leases = store.load().unwrap();
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].id, "a");
+    assert_eq!(leases[0].file_name, "journal_test");
+    assert_eq!(leases[0].hash, "sha256:abc");
+    assert_eq!(leases[0].bytes_left, 2048);
+
+    fs::remove_file(&location).ok();
+  }
+}
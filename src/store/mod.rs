@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn, error};
+
+use crate::Cache;
+use crate::errors::Errors;
+use crate::process::Lease;
+use crate::assembler::{spool, finalize};
+use crate::config::ServerConfig;
+
+pub mod journal;
+
+/// Where leases are kept so they outlive the server. The cache
+/// holds the working set, the store only needs to know which
+/// leases exist. Progress is recovered from the spool files.
+pub trait LeaseStore: Send + Sync {
+  /// Leases saved before the server last stopped.
+  fn load(&self) -> Result<Vec<Lease>, Errors>;
+  fn save(&self, lease: &Lease) -> Result<(), Errors>;
+  fn remove(&self, lease_id: &str) -> Result<(), Errors>;
+  /// Makes sure everything saved so far is durable.
+  fn flush(&self) -> Result<(), Errors> {
+    return Ok(());
+  }
+}
+
+/// Keeps nothing. Leases only live as long as the server.
+pub struct MemoryStore;
+
+impl LeaseStore for MemoryStore {
+  fn load(&self) -> Result<Vec<Lease>, Errors> {
+    return Ok(Vec::new());
+  }
+
+  fn save(&self, _lease: &Lease) -> Result<(), Errors> {
+    return Ok(());
+  }
+
+  fn remove(&self, _lease_id: &str) -> Result<(), Errors> {
+    return Ok(());
+  }
+}
+
+/// Loads the stored leases into the cache, rebuilding their
+/// progress from the spool files. Leases without a spool lost
+/// their data and are removed, spools without a lease too.
+/// Leases whose spool holds the whole file are finalized.
+pub fn reconcile(cache: &Arc<Cache>, config: &ServerConfig) -> Result<(), Errors> {
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .expect("Time went backwards. lol")
+    .as_nanos();
+
+  let mut spools: HashSet<String> = HashSet::new();
+
+  for mut lease in cache.store.load()? {
+    let location = spool::location(&config.incoming_path(), &lease.id, &lease.file_name);
+
+    let records = match spool::index(&location) {
+      Ok(r) => r,
+      Err(_) => {
+        warn!("Dropping lease {} without spool data", lease.id);
+        cache.store.remove(&lease.id)?;
+        continue;
+      }
+    };
+
+    // cut off a record that was only partially
+    // written so new chunks append cleanly.
+    let spool_length = records.last()
+      .map(|r| r.offset + r.length as u64)
+      .unwrap_or(0);
+    let truncated = OpenOptions::new()
+      .write(true)
+      .open(&location)
+      .and_then(|file| file.set_len(spool_length));
+    if truncated.is_err() {
+      return Err(Errors::FileIOError("Failed to truncate spool file".to_string()));
+    }
+
+    lease.chunk_nums.clear();
+    lease.bytes_left = lease.file_length;
+    lease.chunks_sent = 0;
+    lease.last_chunk_num = None;
+    for record in records.iter() {
+      if lease.chunk_nums.insert(record.chunk_num) {
+        lease.bytes_left = lease.bytes_left.saturating_sub(record.length);
+
+        if record.length < config.min_chunk_bytes {
+          lease.last_chunk_num = Some(record.chunk_num);
+        }
+      }
+      lease.chunks_sent += 1;
+    }
+
+    // every chunk was written before the server
+    // stopped, but the finalizer never got to it
+    if lease.bytes_left == 0 {
+      match finalize::finalize_lease(config, cache, &lease) {
+        Ok(()) => info!("Finalized lease {} found complete in its spool", lease.id),
+        Err(e) => error!("Failed to finalize lease {}: {:?}", lease.id, e)
+      };
+      continue;
+    }
+
+    // give the client a full ttl to come back
+    lease.ns_last_sent = now;
+    lease.writers.clear();
+    lease.finalizing = false;
+    spools.insert(location);
+
+    if let Ok(mut leases) = cache.leases.lock() {
+      leases.insert(lease.id.to_string(), lease);
+    } else {
+      return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()));
+    }
+  }
+
+  let swept = spool::sweep(&config.incoming_path(), &spools);
+  if swept > 0 {
+    info!("Swept {} orphaned spool files", swept);
+  }
+
+  return Ok(());
+}
+
+/// Saves every cached lease and flushes the store, for when
+/// the server stops. Only the leases are kept, their chunks
+/// are already in the spool files.
+pub fn persist(cache: &Arc<Cache>) -> Result<(), Errors> {
+  let leases: Vec<Lease> = match cache.leases.lock() {
+    Ok(l) => l.values().cloned().collect(),
+    Err(_) => return Err(Errors::UnexpectedError("Failed to get lock on cached leases".to_string()))
+  };
+
+  for lease in leases.iter() {
+    cache.store.save(lease)?;
+  }
+
+  return cache.store.flush();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+  use std::path::Path;
+  use crate::assembler::checksum::{self, Algorithm};
+  use crate::store::journal::JournalStore;
+
+  #[test]
+  fn finalizes_leases_spooled_whole_before_a_restart() {
+    let storage_dir = std::env::temp_dir().join("rjchunker_reconcile_test");
+    std::fs::remove_dir_all(&storage_dir).ok();
+    let config = ServerConfig { storage_dir: storage_dir.clone(), ..ServerConfig::default() };
+    config.create_dirs().unwrap();
+    let journal = storage_dir.join("leases.journal");
+
+    let data = b"abcdef";
+    let lease = Lease {
+      id: "4a6c8e0f-2b4d-4f6a-8c0e-1a3b5c7d9e0f".to_string(),
+      file_name: "reconcile_test.bin".to_string(),
+      hash: format!("sha256:{}", checksum::digest_bytes(data, Algorithm::SHA256)),
+      file_length: 6,
+      bytes_left: 3,
+      chunks_sent: 1,
+      chunk_nums: [0].iter().copied().collect(),
+      ns_last_sent: 0,
+      writers: HashMap::new(),
+      finalizing: false,
+      last_chunk_num: None
+    };
+    JournalStore::open(&journal).unwrap().save(&lease).unwrap();
+
+    // the last chunk made it to the spool, the finalizer didn't run
+    let location = spool::location(&config.incoming_path(), &lease.id, &lease.file_name);
+    spool::append(&location, &1, &3, b"def").unwrap();
+    spool::append(&location, &0, &3, b"abc").unwrap();
+
+    let cache = Arc::new(Cache::new(Box::new(JournalStore::open(&journal).unwrap()), &config));
+    reconcile(&cache, &config).unwrap();
+
+    assert_eq!(std::fs::read(config.complete_path().join("reconcile_test.bin")).unwrap(), data);
+    assert!(cache.leases.lock().unwrap().is_empty());
+    assert!(cache.store.load().unwrap().is_empty());
+    assert!(!Path::new(&location).exists());
+
+    std::fs::remove_dir_all(&storage_dir).ok();
+  }
+}